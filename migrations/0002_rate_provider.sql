@@ -0,0 +1,3 @@
+-- Record which exchange-rate provider served the latest refresh
+ALTER TABLE `app_status`
+  ADD COLUMN `rate_provider` VARCHAR(64) NULL;
@@ -0,0 +1,16 @@
+-- Every currency a country uses, not just the primary one
+CREATE TABLE IF NOT EXISTS `country_currencies` (
+  `country_id` INT NOT NULL,
+  `currency_code` VARCHAR(10) NOT NULL,
+  `name` VARCHAR(255) NULL,
+  `symbol` VARCHAR(32) NULL,
+  `position` INT NOT NULL, -- 0 is the primary currency
+  PRIMARY KEY (`country_id`, `currency_code`),
+  KEY `idx_country_currencies_code` (`currency_code`),
+  CONSTRAINT `fk_country_currencies_country`
+    FOREIGN KEY (`country_id`) REFERENCES `countries` (`id`) ON DELETE CASCADE
+);
+
+-- Backfill from the primary currency already stored on each country
+INSERT IGNORE INTO `country_currencies` (country_id, currency_code, position)
+SELECT id, currency_code, 0 FROM `countries` WHERE currency_code IS NOT NULL;
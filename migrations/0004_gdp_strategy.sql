@@ -0,0 +1,4 @@
+-- Record how estimated_gdp was computed on the latest refresh
+ALTER TABLE `app_status`
+  ADD COLUMN `gdp_strategy` VARCHAR(16) NULL,
+  ADD COLUMN `gdp_seed` BIGINT UNSIGNED NULL;
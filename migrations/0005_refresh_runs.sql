@@ -0,0 +1,16 @@
+-- One row per POST /countries/refresh, successful or not
+CREATE TABLE IF NOT EXISTS `refresh_runs` (
+  `id` INT AUTO_INCREMENT PRIMARY KEY,
+  `status` VARCHAR(16) NOT NULL, -- running | success | failed
+  `started_at` TIMESTAMP(3) NOT NULL,
+  `finished_at` TIMESTAMP(3) NULL,
+  `duration_ms` BIGINT NULL,
+  `countries_inserted` INT NOT NULL DEFAULT 0,
+  `countries_updated` INT NOT NULL DEFAULT 0,
+  `countries_unchanged` INT NOT NULL DEFAULT 0,
+  `countries_removed` INT NOT NULL DEFAULT 0,
+  `country_provider` VARCHAR(64) NULL,
+  `rate_provider` VARCHAR(64) NULL,
+  `failure_reason` TEXT NULL,
+  KEY `idx_refresh_runs_started_at` (`started_at`)
+);
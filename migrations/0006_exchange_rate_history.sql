@@ -0,0 +1,12 @@
+-- Every rate fetched by every refresh, for charting currency movement
+CREATE TABLE IF NOT EXISTS `exchange_rate_history` (
+  `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+  `currency_code` VARCHAR(10) NOT NULL,
+  `rate` DECIMAL(20, 6) NOT NULL,
+  `fetched_at` TIMESTAMP NOT NULL,
+  `refresh_run` INT NULL,
+  `provider` VARCHAR(64) NULL,
+  KEY `idx_exchange_rate_history_code_time` (`currency_code`, `fetched_at`),
+  CONSTRAINT `fk_exchange_rate_history_run`
+    FOREIGN KEY (`refresh_run`) REFERENCES `refresh_runs` (`id`) ON DELETE SET NULL
+);
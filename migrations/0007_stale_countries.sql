@@ -0,0 +1,3 @@
+-- Set when a country stops appearing upstream and STALE_COUNTRY_POLICY=mark
+ALTER TABLE `countries`
+  ADD COLUMN `stale_since` TIMESTAMP NULL;
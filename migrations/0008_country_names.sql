@@ -0,0 +1,19 @@
+-- Accent- and case-folded name used for lookups; written on every refresh
+ALTER TABLE `countries`
+  ADD COLUMN `name_normalized` VARCHAR(255) NOT NULL DEFAULT '',
+  ADD KEY `idx_countries_name_normalized` (`name_normalized`);
+
+-- Close enough until the next refresh stores the real value
+UPDATE `countries` SET `name_normalized` = LOWER(`name`);
+
+-- Alternative names a country can be looked up by (restcountries `altSpellings`)
+CREATE TABLE IF NOT EXISTS `country_aliases` (
+  `country_id` INT NOT NULL,
+  `alias` VARCHAR(255) NOT NULL,
+  `alias_normalized` VARCHAR(255) NOT NULL,
+  `position` INT NOT NULL,
+  PRIMARY KEY (`country_id`, `alias_normalized`),
+  KEY `idx_country_aliases_normalized` (`alias_normalized`),
+  CONSTRAINT `fk_country_aliases_country`
+    FOREIGN KEY (`country_id`) REFERENCES `countries` (`id`) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- Word-prefix search over the fields GET /countries/search looks at
+ALTER TABLE `countries`
+  ADD FULLTEXT KEY `ft_countries_search` (`name`, `capital`, `region`);
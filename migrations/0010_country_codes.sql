@@ -0,0 +1,8 @@
+-- ISO 3166-1 codes from restcountries; NULL when upstream has none
+ALTER TABLE `countries`
+  ADD COLUMN `alpha2_code` CHAR(2) NULL AFTER `name`,
+  ADD COLUMN `alpha3_code` CHAR(3) NULL AFTER `alpha2_code`,
+  ADD COLUMN `numeric_code` CHAR(3) NULL AFTER `alpha3_code`,
+  ADD UNIQUE KEY `uq_countries_alpha2` (`alpha2_code`),
+  ADD UNIQUE KEY `uq_countries_alpha3` (`alpha3_code`),
+  ADD UNIQUE KEY `uq_countries_numeric` (`numeric_code`);
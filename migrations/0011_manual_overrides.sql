@@ -0,0 +1,7 @@
+-- Set on countries written through POST/PUT/PATCH /countries; a refresh leaves
+-- them alone unless called with ?force=true
+ALTER TABLE `countries`
+  ADD COLUMN `manual_override` BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE `refresh_runs`
+  ADD COLUMN `countries_skipped` INT NOT NULL DEFAULT 0 AFTER `countries_removed`;
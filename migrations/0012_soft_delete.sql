@@ -0,0 +1,5 @@
+-- Set by DELETE /countries/{id}; refreshes leave deleted countries alone
+-- until POST /countries/{id}/restore clears it
+ALTER TABLE `countries`
+  ADD COLUMN `deleted_at` TIMESTAMP NULL,
+  ADD KEY `idx_countries_deleted_at` (`deleted_at`);
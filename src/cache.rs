@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// HTTP cache validators returned by an upstream.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// A previously fetched upstream body.
+pub struct CachedBody {
+    pub body: Vec<u8>,
+    pub validators: Validators,
+    pub fetched_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheMeta {
+    url: String,
+    #[serde(flatten)]
+    validators: Validators,
+    fetched_at: DateTime<Utc>,
+}
+
+/// On-disk copy of the last good response from one upstream.
+///
+/// Stored as `<dir>/<key>.body` plus `<dir>/<key>.meta.json`. The metadata
+/// records the URL the body came from, so changing an endpoint invalidates it.
+#[derive(Clone)]
+pub struct UpstreamCache {
+    dir: PathBuf,
+    key: String,
+}
+
+impl UpstreamCache {
+    pub fn new(dir: impl Into<PathBuf>, key: &str) -> Self {
+        UpstreamCache {
+            dir: dir.into(),
+            key: key.to_lowercase(),
+        }
+    }
+
+    /// Loads the cached body for `url`, if a complete and matching entry exists.
+    pub fn load(&self, url: &str) -> Option<CachedBody> {
+        let meta_data = fs::read(self.meta_path()).ok()?;
+        let meta: CacheMeta = match serde_json::from_slice(&meta_data) {
+            Ok(meta) => meta,
+            Err(e) => {
+                log::warn!("Ignoring corrupt cache metadata for {}: {}", self.key, e);
+                return None;
+            }
+        };
+        if meta.url != url {
+            return None;
+        }
+        let body = fs::read(self.body_path()).ok()?;
+        Some(CachedBody {
+            body,
+            validators: meta.validators,
+            fetched_at: meta.fetched_at,
+        })
+    }
+
+    /// Persists `body`, its validators and when it was fetched. Failures are
+    /// logged, not returned: the cache is an optimisation and must never fail
+    /// a refresh.
+    pub fn store(
+        &self,
+        url: &str,
+        validators: &Validators,
+        body: &[u8],
+        fetched_at: DateTime<Utc>,
+    ) {
+        let meta = CacheMeta {
+            url: url.to_string(),
+            validators: validators.clone(),
+            fetched_at,
+        };
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| write_atomic(&self.body_path(), body))
+            .and_then(|_| {
+                let meta_data = serde_json::to_vec_pretty(&meta)?;
+                write_atomic(&self.meta_path(), &meta_data)
+            });
+        if let Err(e) = result {
+            log::warn!("Failed to cache response for {}: {}", self.key, e);
+        }
+    }
+
+    fn body_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.body", self.key))
+    }
+
+    fn meta_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.meta.json", self.key))
+    }
+}
+
+/// Writes via a temporary file and rename so readers never see a partial file.
+fn write_atomic(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
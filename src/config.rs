@@ -1,9 +1,94 @@
+use crate::db::MAX_REFRESH_BATCH_SIZE;
+use crate::external::{BreakerSettings, RetryPolicy};
 use std::env;
+use std::time::Duration;
+
+/// Where refresh data is loaded from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataSource {
+    /// Live upstream APIs (default).
+    Http,
+    /// Local JSON fixture files, re-read on every refresh.
+    File,
+    /// Local JSON fixture files, loaded once at startup.
+    Memory,
+}
+
+/// Which RestCountries response schema the country source speaks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CountriesSchema {
+    /// `/v2/all`: `currencies` is an array, `capital` a string.
+    V2,
+    /// `/v3.1/all`: `currencies` is a keyed map, `capital` an array.
+    V3_1,
+}
+
+/// How the GDP multiplier is chosen on refresh.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GdpStrategy {
+    /// A new random multiplier every refresh (default).
+    Random,
+    /// A random multiplier derived from the seed and the country name.
+    Seeded { seed: u64 },
+    /// The same multiplier for every country.
+    Fixed { multiplier: f64 },
+}
+
+/// What a refresh does with stored countries that upstream no longer returns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StalePolicy {
+    /// Delete them.
+    Delete,
+    /// Keep them but set `stale_since` (default).
+    Mark,
+    /// Leave them untouched.
+    Keep,
+}
+
+impl StalePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StalePolicy::Delete => "delete",
+            StalePolicy::Mark => "mark",
+            StalePolicy::Keep => "keep",
+        }
+    }
+}
+
+/// Settings applied by `db::refresh_data`.
+#[derive(Clone, Debug)]
+pub struct RefreshSettings {
+    pub stale_policy: StalePolicy,
+    /// Countries written per multi-row upsert.
+    pub batch_size: usize,
+}
+
+/// An exchange-rate provider in the fallback chain.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RateProvider {
+    /// open.er-api.com JSON API.
+    OpenErApi,
+    /// European Central Bank daily reference rates (XML).
+    Ecb,
+    /// The static rates file at `RATES_FIXTURE_PATH`.
+    File,
+}
 
 #[derive(Clone)]
 pub struct Config {
     pub database_url: String,
     pub port: u16,
+    pub data_source: DataSource,
+    pub countries_schema: CountriesSchema,
+    pub countries_fixture_path: String,
+    pub rates_fixture_path: String,
+    pub rate_providers: Vec<RateProvider>,
+    pub upstream_cache_dir: String,
+    pub gdp_strategy: GdpStrategy,
+    pub refresh: RefreshSettings,
+    pub http_timeout: Duration,
+    pub retry: RetryPolicy,
+    pub breaker: BreakerSettings,
 }
 
 impl Config {
@@ -14,7 +99,127 @@ impl Config {
             .unwrap_or_else(|_| "8080".to_string())
             .parse::<u16>()
             .expect("PORT must be a valid number");
+        let data_source = match env::var("DATA_SOURCE")
+            .unwrap_or_else(|_| "http".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "http" => DataSource::Http,
+            "file" => DataSource::File,
+            "memory" => DataSource::Memory,
+            other => panic!("DATA_SOURCE must be one of http, file, memory (got '{}')", other),
+        };
+        let countries_schema = match env::var("COUNTRIES_SCHEMA")
+            .unwrap_or_else(|_| "v2".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "v2" => CountriesSchema::V2,
+            "v3.1" | "v3" => CountriesSchema::V3_1,
+            other => panic!("COUNTRIES_SCHEMA must be one of v2, v3.1 (got '{}')", other),
+        };
+        let countries_fixture_path = env::var("COUNTRIES_FIXTURE_PATH")
+            .unwrap_or_else(|_| "fixtures/countries.json".to_string());
+        let rates_fixture_path = env::var("RATES_FIXTURE_PATH")
+            .unwrap_or_else(|_| "fixtures/rates.json".to_string());
 
-        Config { database_url, port }
+        let rate_providers: Vec<RateProvider> = env::var("RATE_PROVIDERS")
+            .unwrap_or_else(|_| "open_er_api,ecb".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| match s.to_lowercase().as_str() {
+                "open_er_api" => RateProvider::OpenErApi,
+                "ecb" => RateProvider::Ecb,
+                "file" => RateProvider::File,
+                other => panic!(
+                    "RATE_PROVIDERS entries must be one of open_er_api, ecb, file (got '{}')",
+                    other
+                ),
+            })
+            .collect();
+        assert!(!rate_providers.is_empty(), "RATE_PROVIDERS must not be empty");
+        let upstream_cache_dir = env::var("UPSTREAM_CACHE_DIR")
+            .unwrap_or_else(|_| "cache/upstream".to_string());
+        let gdp_strategy = match env::var("GDP_STRATEGY")
+            .unwrap_or_else(|_| "random".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "random" => GdpStrategy::Random,
+            "seeded" => GdpStrategy::Seeded {
+                seed: parse_env("GDP_SEED", 0),
+            },
+            "fixed" => GdpStrategy::Fixed {
+                multiplier: parse_env("GDP_MULTIPLIER", 1500.0),
+            },
+            other => panic!("GDP_STRATEGY must be one of random, seeded, fixed (got '{}')", other),
+        };
+        let stale_policy = match env::var("STALE_COUNTRY_POLICY")
+            .unwrap_or_else(|_| "mark".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "delete" => StalePolicy::Delete,
+            "mark" => StalePolicy::Mark,
+            "keep" => StalePolicy::Keep,
+            other => panic!(
+                "STALE_COUNTRY_POLICY must be one of delete, mark, keep (got '{}')",
+                other
+            ),
+        };
+        let refresh = RefreshSettings {
+            stale_policy,
+            batch_size: parse_env("REFRESH_BATCH_SIZE", 100usize).clamp(1, MAX_REFRESH_BATCH_SIZE),
+        };
+        let http_timeout = Duration::from_secs(parse_env("HTTP_TIMEOUT_SECS", 30));
+        let retry = RetryPolicy {
+            max_attempts: parse_env("RETRY_MAX_ATTEMPTS", 3),
+            base_delay: Duration::from_millis(parse_env("RETRY_BASE_DELAY_MS", 500)),
+            max_delay: Duration::from_millis(parse_env("RETRY_MAX_DELAY_MS", 10_000)),
+            jitter: Duration::from_millis(parse_env("RETRY_JITTER_MS", 250)),
+            retryable_statuses: env::var("RETRY_STATUS_CODES")
+                .unwrap_or_else(|_| "408,429,500,502,503,504".to_string())
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    s.parse::<u16>()
+                        .expect("RETRY_STATUS_CODES must be a comma-separated list of status codes")
+                })
+                .collect(),
+            retry_connect_errors: parse_env("RETRY_ON_CONNECT_ERROR", true),
+            retry_timeouts: parse_env("RETRY_ON_TIMEOUT", true),
+        };
+        let breaker = BreakerSettings {
+            failure_threshold: parse_env("BREAKER_FAILURE_THRESHOLD", 5u32).max(1),
+            cooldown: Duration::from_secs(parse_env("BREAKER_COOLDOWN_SECS", 60)),
+        };
+
+        Config {
+            database_url,
+            port,
+            data_source,
+            countries_schema,
+            countries_fixture_path,
+            rates_fixture_path,
+            rate_providers,
+            upstream_cache_dir,
+            gdp_strategy,
+            refresh,
+            http_timeout,
+            retry,
+            breaker,
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Reads and parses an optional environment variable, panicking on malformed values.
+fn parse_env<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value
+            .parse::<T>()
+            .unwrap_or_else(|_| panic!("{} has an invalid value: '{}'", key, value)),
+        Err(_) => default,
+    }
+}
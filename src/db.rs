@@ -1,103 +1,275 @@
+use crate::config::StalePolicy;
 use crate::error::AppError;
+use crate::gdp::GdpEstimator;
+use crate::names;
+use crate::pagination::{Keyset, Page, PageStart};
+use crate::validation::invalid;
 use crate::models::{
-    AppStatus, Country, GetCountriesQuery, RestCountryResponse,
+    AppStatus, Country, CountryCurrency, CountryRecord, CurrencyInfo, CurrencySummary, FetchedRates,
+    CountryFilters, RateHistoryPoint, SortKey, RefreshOutcome, RefreshRun, RefreshRunSummary,
+    RefreshStats, RegionSummary, ComparisonMetrics, CountryComparison,
 };
-use chrono::{Utc, SubsecRound};
-use rand::Rng;
-use sqlx::{MySqlPool, QueryBuilder};
-use std::collections::HashMap;
+use chrono::{DateTime, SubsecRound, Utc};
+use sqlx::types::Decimal;
+use sqlx::{FromRow, MySql, MySqlConnection, MySqlPool, QueryBuilder};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// Rows per multi-row INSERT into `exchange_rate_history`.
+const RATE_HISTORY_CHUNK_SIZE: usize = 500;
+
+/// Upper bound for `RefreshOptions::batch_size`. A country row binds 14
+/// parameters and MySQL allows at most 65,535 per statement.
+pub const MAX_REFRESH_BATCH_SIZE: usize = 1000;
+
+/// Columns compared to decide whether a refresh changed a stored country.
+#[derive(Debug, Clone, PartialEq, FromRow)]
+struct CountryFields {
+    name: String,
+    alpha2_code: Option<String>,
+    alpha3_code: Option<String>,
+    numeric_code: Option<String>,
+    capital: Option<String>,
+    region: Option<String>,
+    population: i64,
+    currency_code: Option<String>,
+    exchange_rate: Option<Decimal>,
+    estimated_gdp: Option<Decimal>,
+    flag_url: Option<String>,
+    manual_override: bool,
+}
+
+/// What a refresh compares each incoming country against.
+struct StoredCountry {
+    fields: CountryFields,
+    /// Compared in full, so a changed currency name or symbol is written too.
+    currencies: Vec<CurrencyInfo>,
+    aliases: Vec<String>,
+}
+
+/// Per-run inputs to `refresh_data` besides the fetched data.
+pub struct RefreshOptions<'a> {
+    /// The `refresh_runs` row this refresh belongs to.
+    pub run_id: i32,
+    pub estimator: &'a dyn GdpEstimator,
+    pub stale_policy: StalePolicy,
+    /// Rows per multi-row upsert; `1` writes one country per round trip.
+    pub batch_size: usize,
+    /// Overwrite manual overrides too, clearing their flag.
+    pub force: bool,
+}
 
 /// Processes and caches all country and rate data in the database.
 pub async fn refresh_data(
     pool: &MySqlPool,
-    countries: Vec<RestCountryResponse>,
-    rates: HashMap<String, f64>,
-) -> Result<(AppStatus, Vec<Country>), AppError> {
+    mut countries: Vec<CountryRecord>,
+    rates: &FetchedRates,
+    options: &RefreshOptions<'_>,
+) -> Result<RefreshOutcome, AppError> {
+    let estimator = options.estimator;
     let mut tx = pool.begin().await?;
-    let refresh_time = Utc::now().round_subsecs(1);
-    let mut country_count = 0;
-    let mut rng = rand::thread_rng();
+    // Whole seconds, matching the TIMESTAMP columns exactly so the stale check
+    // below can compare against what was actually stored.
+    let refresh_time = Utc::now().trunc_subsecs(0);
+    let country_count = countries.len();
+    let mut stats = RefreshStats::default();
 
-    for country in countries {
-        country_count += 1;
+    // Current state, used to classify each country as inserted/updated/unchanged.
+    // Keyed by `names::collation_key`, the way the unique index compares names.
+    let mut existing: HashMap<String, StoredCountry> =
+        sqlx::query_as::<_, CountryFields>(
+            r#"
+            SELECT name, alpha2_code, alpha3_code, numeric_code, capital, region,
+                   population, currency_code, exchange_rate, estimated_gdp, flag_url,
+                   manual_override
+            FROM countries
+            "#,
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|fields| {
+            let stored = StoredCountry {
+                fields,
+                currencies: Vec::new(),
+                aliases: Vec::new(),
+            };
+            (names::collation_key(&stored.fields.name), stored)
+        })
+        .collect();
+    let currency_rows: Vec<(String, String, Option<String>, Option<String>)> = sqlx::query_as(
+        r#"
+        SELECT c.name, cc.currency_code, cc.name, cc.symbol
+        FROM country_currencies cc
+        JOIN countries c ON c.id = cc.country_id
+        ORDER BY cc.country_id, cc.position
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for (country, code, name, symbol) in currency_rows {
+        if let Some(stored) = existing.get_mut(&names::collation_key(&country)) {
+            stored.currencies.push(CurrencyInfo { code, name, symbol });
+        }
+    }
+    let alias_rows: Vec<(String, String)> = sqlx::query_as(
+        r#"
+        SELECT c.name, a.alias
+        FROM country_aliases a
+        JOIN countries c ON c.id = a.country_id
+        ORDER BY a.country_id, a.position
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for (name, alias) in alias_rows {
+        if let Some(stored) = existing.get_mut(&names::collation_key(&name)) {
+            stored.aliases.push(alias);
+        }
+    }
 
-        // 1. Determine Currency Code
-        let currency_code = country
+    // Deleted countries stay deleted, even when forced
+    let tombstones: HashSet<String> =
+        sqlx::query_as::<_, (String,)>("SELECT name FROM countries WHERE deleted_at IS NOT NULL")
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(|(name,)| names::collation_key(&name))
+            .collect();
+
+    // Manual overrides keep their data, and their ISO codes, unless forced.
+    // Deleted countries keep their ISO codes until they are restored.
+    let mut reserved: Vec<&CountryFields> = Vec::new();
+    if !options.force {
+        reserved.extend(existing.values().map(|s| &s.fields).filter(|f| f.manual_override));
+    }
+    reserved.extend(
+        existing
+            .iter()
+            .filter(|(key, _)| tombstones.contains(*key))
+            .map(|(_, stored)| &stored.fields),
+    );
+    countries.retain(|country| {
+        let key = names::collation_key(&country.name);
+        let manual = !options.force
+            && existing
+                .get(&key)
+                .is_some_and(|stored| stored.fields.manual_override);
+        let skip = manual || tombstones.contains(&key);
+        if skip {
+            stats.skipped += 1;
+        }
+        !skip
+    });
+
+    // ISO codes are unique: settle conflicts before anything is written
+    normalize_codes(&mut countries, &reserved);
+    let released = codes_to_release(&existing, &countries);
+    release_codes(&mut tx, &released).await?;
+
+    let mut rows: Vec<CountryFields> = Vec::with_capacity(countries.len());
+    let mut currency_updates: HashMap<String, Vec<CurrencyInfo>> = HashMap::new();
+    let mut alias_updates: HashMap<String, Vec<String>> = HashMap::new();
+    for country in countries {
+        let rate = country
             .currencies
-            .as_ref()
-            .and_then(|c| c.first())
-            .map(|c| c.code.clone());
-
-        // 2. Determine Exchange Rate & GDP
-        let (exchange_rate, estimated_gdp) = match &currency_code {
-            Some(code) => {
-                if let Some(rate) = rates.get(code) {
-                    // Currency found in rates
-                    let random_multiplier: f64 = rng.gen_range(1000.0..=2000.0);
-                    let gdp = (country.population as f64 * random_multiplier) / rate;
-                    (Some(*rate), Some(gdp))
+            .first()
+            .and_then(|c| rates.rates.get(&c.code).copied());
+        let fields = country_fields(&country, rate, estimator, false);
+        let currencies: Vec<CurrencyInfo> = unique_currencies(&country.currencies)
+            .into_iter()
+            .cloned()
+            .collect();
+        let aliases = names::aliases(&fields.name, &country.alt_spellings);
+        let key = names::collation_key(&fields.name);
+        let (currencies_changed, aliases_changed) = match existing.get(&key) {
+            None => {
+                stats.inserted += 1;
+                (true, true)
+            }
+            Some(stored) => {
+                let currencies_changed = stored.currencies != currencies;
+                let aliases_changed = stored.aliases != aliases;
+                if stored.fields == fields && !currencies_changed && !aliases_changed {
+                    stats.unchanged += 1;
                 } else {
-                    // Currency code exists but not in rates API
-                    (None, None)
+                    stats.updated += 1;
                 }
-            }
-            None => {
-                // No currency array or it's empty
-                (None, Some(0.0))
+                (currencies_changed, aliases_changed)
             }
         };
+        if currencies_changed {
+            currency_updates.insert(fields.name.clone(), country.currencies);
+        }
+        if aliases_changed {
+            alias_updates.insert(fields.name.clone(), aliases.clone());
+        }
+        existing.insert(
+            key,
+            StoredCountry {
+                fields: fields.clone(),
+                currencies,
+                aliases,
+            },
+        );
+        rows.push(fields);
+    }
 
-        // 3. Upsert logic
-        // Use `INSERT ... ON DUPLICATE KEY UPDATE` for efficient upsert
-        sqlx::query(
-            r#"
-            INSERT INTO countries (
-                name, capital, region, population, currency_code, 
-                exchange_rate, estimated_gdp, flag_url, last_refreshed_at
-            )
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
-            ON DUPLICATE KEY UPDATE
-                capital = VALUES(capital),
-                region = VALUES(region),
-                population = VALUES(population),
-                currency_code = VALUES(currency_code),
-                exchange_rate = VALUES(exchange_rate),
-                estimated_gdp = VALUES(estimated_gdp),
-                flag_url = VALUES(flag_url),
-                last_refreshed_at = VALUES(last_refreshed_at)
-            "#,
-        )
-        .bind(country.name)
-        .bind(country.capital)
-        .bind(country.region)
-        .bind(country.population)
-        .bind(currency_code)
-        .bind(exchange_rate)
-        .bind(estimated_gdp)
-        .bind(country.flag)
-        .bind(refresh_time)
-        .execute(&mut *tx)
-        .await?;
+    // 3. Upsert in chunks of `batch_size` rows.
+    // Unchanged rows are still written so `last_refreshed_at` moves forward.
+    let batch_size = options.batch_size.max(1);
+    for chunk in rows.chunks(batch_size) {
+        upsert_countries(&mut tx, chunk, refresh_time).await?;
     }
 
-    // 4. Update app status
+    // 4. Replace the currency and alias lists that changed
+    replace_currencies(&mut tx, &currency_updates, batch_size).await?;
+    replace_aliases(&mut tx, &alias_updates, batch_size).await?;
+
+    // 5. Reconcile countries that upstream no longer returns
+    let stale_countries = if country_count > 0 {
+        reconcile_stale_countries(&mut tx, refresh_time, options.stale_policy).await?
+    } else {
+        // An empty upstream list is far more likely a bad payload than a
+        // world without countries; don't wipe the table over it.
+        log::warn!("Refresh returned no countries; skipping stale reconciliation");
+        Vec::new()
+    };
+    if options.stale_policy != StalePolicy::Keep {
+        stats.removed = stale_countries.len() as i32;
+    }
+
+    // 6. Keep a snapshot of every fetched rate
+    record_rate_history(&mut tx, options.run_id, rates).await?;
+
+    // 7. Update app status
+    let (total_countries,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM countries WHERE stale_since IS NULL AND deleted_at IS NULL")
+            .fetch_one(&mut *tx)
+            .await?;
+    let total_countries = total_countries as i32;
+
     sqlx::query(
         r#"
         UPDATE app_status 
-        SET total_countries = ?, last_refreshed_at = ? 
+        SET total_countries = ?, last_refreshed_at = ?, rate_provider = ?,
+            gdp_strategy = ?, gdp_seed = ?
         WHERE id = 1
         "#,
     )
-    .bind(country_count)
+    .bind(total_countries)
     .bind(refresh_time)
+    .bind(&rates.provider)
+    .bind(estimator.name())
+    .bind(estimator.seed())
     .execute(&mut *tx)
     .await?;
 
-    // 5. Fetch top 5 countries for image generation
+    // 8. Fetch top 5 countries for image generation
     let top_countries = sqlx::query_as::<_, Country>(
         r#"
         SELECT * FROM countries 
-        WHERE estimated_gdp IS NOT NULL
+        WHERE estimated_gdp IS NOT NULL AND stale_since IS NULL AND deleted_at IS NULL
         ORDER BY estimated_gdp DESC 
         LIMIT 5
         "#,
@@ -105,97 +277,1612 @@ pub async fn refresh_data(
     .fetch_all(&mut *tx)
     .await?;
 
-    // 6. Commit transaction
+    // 9. Commit transaction
     tx.commit().await?;
 
     let status = AppStatus {
-        total_countries: country_count,
+        total_countries,
         last_refreshed_at: Some(refresh_time),
+        rate_provider: Some(rates.provider.clone()),
+        gdp_strategy: Some(estimator.name().to_string()),
+        gdp_seed: estimator.seed(),
+    };
+
+    Ok(RefreshOutcome {
+        status,
+        top_countries,
+        stats,
+        stale_countries,
+    })
+}
+
+/// Applies `policy` to countries not touched by the refresh at `refresh_time`
+/// and returns the names of those it marks or deletes; countries marked by an
+/// earlier run are not reported again. Under `keep` every such country is
+/// returned. Manual overrides and deleted countries are never stale.
+async fn reconcile_stale_countries(
+    conn: &mut MySqlConnection,
+    refresh_time: DateTime<Utc>,
+    policy: StalePolicy,
+) -> Result<Vec<String>, AppError> {
+    let already_marked = if policy == StalePolicy::Mark {
+        " AND stale_since IS NULL"
+    } else {
+        ""
+    };
+    let stale: Vec<(String,)> = sqlx::query_as(&format!(
+        r#"
+        SELECT name FROM countries
+        WHERE last_refreshed_at < ? AND NOT manual_override AND deleted_at IS NULL{}
+        ORDER BY name
+        "#,
+        already_marked
+    ))
+    .bind(refresh_time)
+    .fetch_all(&mut *conn)
+    .await?;
+    let names: Vec<String> = stale.into_iter().map(|(name,)| name).collect();
+    if names.is_empty() {
+        return Ok(names);
+    }
+
+    match policy {
+        StalePolicy::Delete => {
+            sqlx::query(
+                r#"
+                DELETE FROM countries
+                WHERE last_refreshed_at < ? AND NOT manual_override AND deleted_at IS NULL
+                "#,
+            )
+                .bind(refresh_time)
+                .execute(&mut *conn)
+                .await?;
+        }
+        StalePolicy::Mark => {
+            sqlx::query(
+                r#"
+                UPDATE countries SET stale_since = ?
+                WHERE last_refreshed_at < ? AND NOT manual_override AND deleted_at IS NULL
+                  AND stale_since IS NULL
+                "#,
+            )
+            .bind(refresh_time)
+            .bind(refresh_time)
+            .execute(&mut *conn)
+            .await?;
+        }
+        StalePolicy::Keep => {}
+    }
+    log::info!(
+        "{} stale countries ({}): {}",
+        names.len(),
+        policy.as_str(),
+        names.join(", ")
+    );
+    Ok(names)
+}
+
+/// Appends every fetched rate to `exchange_rate_history`, stamped with when
+/// the provider served it rather than when the refresh ran.
+///
+/// A snapshot re-served by the fallback was already recorded by the refresh
+/// that fetched it, so a batch whose fetch time is already in the table is
+/// skipped instead of showing up as new points.
+async fn record_rate_history(
+    conn: &mut MySqlConnection,
+    run_id: i32,
+    rates: &FetchedRates,
+) -> Result<(), AppError> {
+    let fetched_at = rates.fetched_at.trunc_subsecs(0);
+    let mut entries: Vec<(&String, Decimal)> = rates
+        .rates
+        .iter()
+        .filter_map(|(code, rate)| to_decimal(*rate).map(|rate| (code, rate)))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    if let Some((code, _)) = entries.first() {
+        let recorded: Option<(i64,)> = sqlx::query_as(
+            "SELECT id FROM exchange_rate_history WHERE currency_code = ? AND fetched_at = ? LIMIT 1",
+        )
+        .bind(*code)
+        .bind(fetched_at)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if recorded.is_some() {
+            log::info!(
+                "Rates from {} fetched at {} are already recorded; skipping history",
+                rates.provider,
+                fetched_at
+            );
+            return Ok(());
+        }
+    }
+
+    for chunk in entries.chunks(RATE_HISTORY_CHUNK_SIZE) {
+        let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+            "INSERT INTO exchange_rate_history (currency_code, rate, fetched_at, refresh_run, provider) ",
+        );
+        qb.push_values(chunk, |mut b, (code, rate)| {
+            b.push_bind(*code)
+                .push_bind(*rate)
+                .push_bind(fetched_at)
+                .push_bind(run_id)
+                .push_bind(&rates.provider);
+        });
+        qb.build().execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Uppercases the ISO codes of `countries`, drops malformed ones, and drops
+/// any code already claimed by an earlier country in the list or held by one
+/// of the `reserved` stored countries; either would otherwise fail the whole
+/// refresh on the unique indexes.
+fn normalize_codes(countries: &mut [CountryRecord], reserved: &[&CountryFields]) {
+    let mut claimed: HashMap<(&'static str, String), String> = HashMap::new();
+    for fields in reserved {
+        for (kind, code) in iso_codes(&fields.alpha2_code, &fields.alpha3_code, &fields.numeric_code) {
+            claimed.insert((kind, code.to_string()), fields.name.clone());
+        }
+    }
+    for country in countries.iter_mut() {
+        let codes = [
+            ("alpha2", &mut country.alpha2_code),
+            ("alpha3", &mut country.alpha3_code),
+            ("numeric", &mut country.numeric_code),
+        ];
+        for (kind, code) in codes {
+            let Some(value) = code.take() else { continue };
+            let value = value.trim().to_uppercase();
+            let well_formed = match kind {
+                "alpha2" => value.len() == 2 && value.chars().all(|c| c.is_ascii_uppercase()),
+                "alpha3" => value.len() == 3 && value.chars().all(|c| c.is_ascii_uppercase()),
+                _ => value.len() == 3 && value.chars().all(|c| c.is_ascii_digit()),
+            };
+            if !well_formed {
+                log::warn!("Ignoring malformed {} code '{}' for {}", kind, value, country.name);
+                continue;
+            }
+            match claimed.get(&(kind, value.clone())) {
+                Some(owner) if names::collation_key(owner) != names::collation_key(&country.name) => {
+                    log::warn!(
+                        "Ignoring {} code {} for {}: already used by {}",
+                        kind, value, country.name, owner
+                    );
+                }
+                _ => {
+                    claimed.insert((kind, value.clone()), country.name.clone());
+                    *code = Some(value);
+                }
+            }
+        }
+    }
+}
+
+/// Names of stored countries holding an ISO code that `countries` assigns to
+/// a different country, e.g. after upstream renamed a country.
+fn codes_to_release(
+    existing: &HashMap<String, StoredCountry>,
+    countries: &[CountryRecord],
+) -> Vec<String> {
+    let mut owners: HashMap<(&str, &str), String> = HashMap::new();
+    for country in countries {
+        for (kind, code) in iso_codes(&country.alpha2_code, &country.alpha3_code, &country.numeric_code) {
+            owners.insert((kind, code), names::collation_key(&country.name));
+        }
+    }
+    existing
+        .iter()
+        .filter(|(name_key, stored)| {
+            let fields = &stored.fields;
+            iso_codes(&fields.alpha2_code, &fields.alpha3_code, &fields.numeric_code)
+                .any(|key| owners.get(&key).is_some_and(|owner| owner != *name_key))
+        })
+        .map(|(_, stored)| stored)
+        .map(|stored| stored.fields.name.clone())
+        .collect()
+}
+
+/// The set codes as `(kind, code)` pairs.
+fn iso_codes<'a>(
+    alpha2: &'a Option<String>,
+    alpha3: &'a Option<String>,
+    numeric: &'a Option<String>,
+) -> impl Iterator<Item = (&'static str, &'a str)> {
+    [("alpha2", alpha2), ("alpha3", alpha3), ("numeric", numeric)]
+        .into_iter()
+        .filter_map(|(kind, code)| code.as_deref().map(|code| (kind, code)))
+}
+
+/// Clears the ISO codes of the countries called `names`.
+async fn release_codes(conn: &mut MySqlConnection, names: &[String]) -> Result<(), AppError> {
+    if names.is_empty() {
+        return Ok(());
+    }
+    log::info!("Releasing ISO codes now used by other countries from: {}", names.join(", "));
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        "UPDATE countries SET alpha2_code = NULL, alpha3_code = NULL, numeric_code = NULL WHERE name IN (",
+    );
+    let mut separated = qb.separated(", ");
+    for name in names {
+        separated.push_bind(name);
+    }
+    qb.push(")");
+    qb.build().execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// The stored columns for `country`, given the USD rate of its primary
+/// currency if there is one.
+fn country_fields(
+    country: &CountryRecord,
+    rate: Option<f64>,
+    estimator: &dyn GdpEstimator,
+    manual_override: bool,
+) -> CountryFields {
+    // 1. Determine Currency Code
+    let currency_code = country.currencies.first().map(|c| c.code.clone());
+
+    // 2. Determine Exchange Rate & GDP
+    let (exchange_rate, estimated_gdp) = match (&currency_code, rate) {
+        (Some(_), Some(rate)) => {
+            let gdp = estimator.estimate(&country.name, country.population, rate);
+            (to_decimal(rate), to_decimal(gdp))
+        }
+        // Currency code exists but has no known rate
+        (Some(_), None) => (None, None),
+        // No currencies listed
+        (None, _) => (None, Some(Decimal::ZERO)),
     };
 
-    Ok((status, top_countries))
+    CountryFields {
+        name: country.name.clone(),
+        alpha2_code: country.alpha2_code.clone(),
+        alpha3_code: country.alpha3_code.clone(),
+        numeric_code: country.numeric_code.clone(),
+        capital: country.capital.clone(),
+        region: country.region.clone(),
+        population: country.population,
+        currency_code,
+        exchange_rate,
+        estimated_gdp,
+        flag_url: country.flag_url.clone(),
+        manual_override,
+    }
+}
+
+/// Rounds to the 6 decimal places stored in the DECIMAL columns. Non-finite
+/// values (e.g. from a zero rate) are stored as NULL.
+fn to_decimal(value: f64) -> Option<Decimal> {
+    Decimal::try_from(value).ok().map(|d| d.round_dp(6))
 }
 
+/// Drops repeated currency codes; some upstream entries list the same code twice.
+fn unique_currencies(currencies: &[CurrencyInfo]) -> Vec<&CurrencyInfo> {
+    let mut seen = HashSet::new();
+    currencies
+        .iter()
+        .filter(|c| seen.insert(c.code.as_str()))
+        .collect()
+}
+
+/// Fields `GET /countries` can be sorted by, as (API name, column).
+pub const SORT_FIELDS: &[(&str, &str)] = &[
+    ("id", "id"),
+    ("name", "name"),
+    ("capital", "capital"),
+    ("region", "region"),
+    ("population", "population"),
+    ("primary_currency", "currency_code"),
+    ("exchange_rate", "exchange_rate"),
+    ("estimated_gdp", "estimated_gdp"),
+    ("last_refreshed_at", "last_refreshed_at"),
+];
+
+/// The original single-key `sort` values and the sort list each stands for.
+pub const LEGACY_SORTS: &[(&str, &str)] = &[
+    ("gdp_desc", "-estimated_gdp"),
+    ("gdp_asc", "estimated_gdp"),
+    ("pop_desc", "-population"),
+    ("pop_asc", "population"),
+    ("name_asc", "name"),
+    ("name_desc", "-name"),
+];
+
 /// Retrieves all countries from the DB, supporting filters and sorting.
+/// Rows are ordered by `sort`, then by `id`, so the order is deterministic.
 pub async fn get_all_countries(
     pool: &MySqlPool,
-    query: GetCountriesQuery,
+    filters: &CountryFilters,
+    sort: &[SortKey],
 ) -> Result<Vec<Country>, AppError> {
     let mut qb: QueryBuilder<sqlx::MySql> = QueryBuilder::new("SELECT * FROM countries");
+    push_country_filters(&mut qb, filters);
+    push_country_order(&mut qb, &sort_order(sort, false));
+    let mut countries = qb.build_query_as().fetch_all(pool).await?;
+    attach_currencies(pool, &mut countries).await?;
+    Ok(countries)
+}
+
+/// Retrieves one page of countries, in the same order as `get_all_countries`.
+///
+/// Also returns whether more rows lie beyond the page in the direction it
+/// was read: after it, or before it for a page read backwards.
+pub async fn get_country_page(
+    pool: &MySqlPool,
+    filters: &CountryFilters,
+    sort: &[SortKey],
+    page: &Page,
+) -> Result<(Vec<Country>, bool), AppError> {
+    let order = sort_order(sort, page.backwards());
+    let mut qb: QueryBuilder<sqlx::MySql> = QueryBuilder::new("SELECT * FROM countries");
+    let has_where = push_country_filters(&mut qb, filters);
+    if let PageStart::After(position) | PageStart::Before(position) = &page.start {
+        qb.push(if has_where { " AND " } else { " WHERE " });
+        push_keyset_condition(&mut qb, &order, position)?;
+    }
+    push_country_order(&mut qb, &order);
+
+    // One extra row tells whether there is more
+    qb.push(" LIMIT ");
+    qb.push_bind(page.limit + 1);
+    if let PageStart::Offset(offset) = page.start {
+        qb.push(" OFFSET ");
+        qb.push_bind(offset);
+    }
+    let mut countries: Vec<Country> = qb.build_query_as().fetch_all(pool).await?;
+    let more = countries.len() as i64 > page.limit;
+    countries.truncate(page.limit as usize);
+    if page.backwards() {
+        countries.reverse();
+    }
+    attach_currencies(pool, &mut countries).await?;
+    Ok((countries, more))
+}
+
+/// Where `country` sits in the order `sort`, for a cursor.
+pub fn sort_position(country: &Country, sort: &[SortKey]) -> Keyset {
+    let values = sort
+        .iter()
+        .map(|key| match key.column {
+            "id" => Some(country.id.to_string()),
+            "name" => Some(country.name.clone()),
+            "capital" => country.capital.clone(),
+            "region" => country.region.clone(),
+            "population" => Some(country.population.to_string()),
+            "currency_code" => country.currency_code.clone(),
+            "exchange_rate" => country.exchange_rate.map(|rate| rate.to_string()),
+            "estimated_gdp" => country.estimated_gdp.map(|gdp| gdp.to_string()),
+            "last_refreshed_at" => Some(country.last_refreshed_at.to_rfc3339()),
+            _ => None,
+        })
+        .collect();
+    Keyset {
+        values,
+        id: country.id,
+    }
+}
+
+/// The `ORDER BY` terms for `sort` as `(column, descending)`, ending with
+/// `id`; every direction is flipped when reading `backwards`.
+fn sort_order(sort: &[SortKey], backwards: bool) -> Vec<(&'static str, bool)> {
+    sort.iter()
+        .map(|key| (key.column, key.descending != backwards))
+        .chain([("id", backwards)])
+        .collect()
+}
+
+fn push_country_order(qb: &mut QueryBuilder<'_, MySql>, order: &[(&'static str, bool)]) {
+    // Columns come from `SORT_FIELDS`, never from the request itself
+    qb.push(" ORDER BY ");
+    let mut terms = qb.separated(", ");
+    for (column, descending) in order {
+        terms.push(*column);
+        terms.push_unseparated(if *descending { " DESC" } else { " ASC" });
+    }
+}
+
+/// A sort-key value from a cursor, typed for its column.
+enum KeyValue {
+    Int(i64),
+    Decimal(Decimal),
+    Time(DateTime<Utc>),
+    Text(String),
+}
+
+impl KeyValue {
+    fn parse(column: &str, value: &str) -> Option<KeyValue> {
+        Some(match column {
+            "id" | "population" => KeyValue::Int(value.parse().ok()?),
+            "exchange_rate" | "estimated_gdp" => KeyValue::Decimal(Decimal::from_str(value).ok()?),
+            "last_refreshed_at" => KeyValue::Time(value.parse().ok()?),
+            _ => KeyValue::Text(value.to_string()),
+        })
+    }
+
+    fn push_bind(&self, qb: &mut QueryBuilder<'_, MySql>) {
+        match self {
+            KeyValue::Int(value) => qb.push_bind(*value),
+            KeyValue::Decimal(value) => qb.push_bind(*value),
+            KeyValue::Time(value) => qb.push_bind(*value),
+            KeyValue::Text(value) => qb.push_bind(value.clone()),
+        };
+    }
+}
+
+/// Appends the condition for rows that come strictly after `position` in
+/// `order`: `(a > ?) OR (a = ? AND b > ?) OR ... OR (a = ? AND ... AND id > ?)`.
+/// MySQL sorts NULLs first ascending and last descending, and the
+/// comparisons follow suit.
+fn push_keyset_condition(
+    qb: &mut QueryBuilder<'_, MySql>,
+    order: &[(&'static str, bool)],
+    position: &Keyset,
+) -> Result<(), AppError> {
+    let invalid_cursor = || invalid("cursor", "is not a valid cursor");
+    if position.values.len() + 1 != order.len() {
+        return Err(invalid_cursor());
+    }
+    let mut values = Vec::with_capacity(order.len());
+    for ((column, _), value) in order.iter().zip(&position.values) {
+        let value = match value {
+            Some(value) => Some(KeyValue::parse(column, value).ok_or_else(invalid_cursor)?),
+            None => None,
+        };
+        values.push(value);
+    }
+    values.push(Some(KeyValue::Int(i64::from(position.id))));
+
+    qb.push("(");
+    for (i, ((column, descending), value)) in order.iter().zip(&values).enumerate() {
+        if i > 0 {
+            qb.push(" OR ");
+        }
+        qb.push("(");
+        for ((equal_column, _), equal_value) in order.iter().zip(&values).take(i) {
+            match equal_value {
+                Some(value) => {
+                    qb.push(format!("{} = ", equal_column));
+                    value.push_bind(qb);
+                }
+                None => {
+                    qb.push(format!("{} IS NULL", equal_column));
+                }
+            }
+            qb.push(" AND ");
+        }
+        match (value, descending) {
+            (Some(value), false) => {
+                qb.push(format!("{} > ", column));
+                value.push_bind(qb);
+            }
+            (Some(value), true) => {
+                qb.push(format!("({} < ", column));
+                value.push_bind(qb);
+                qb.push(format!(" OR {} IS NULL)", column));
+            }
+            (None, false) => {
+                qb.push(format!("{} IS NOT NULL", column));
+            }
+            // Nothing sorts after NULL in a descending column but other NULLs
+            (None, true) => {
+                qb.push("FALSE");
+            }
+        }
+        qb.push(")");
+    }
+    qb.push(")");
+    Ok(())
+}
+
+/// Counts the countries matching `filters`.
+pub async fn count_countries(pool: &MySqlPool, filters: &CountryFilters) -> Result<i64, AppError> {
+    let mut qb: QueryBuilder<sqlx::MySql> = QueryBuilder::new("SELECT COUNT(*) FROM countries");
+    push_country_filters(&mut qb, filters);
+    let (total,): (i64,) = qb.build_query_as().fetch_one(pool).await?;
+    Ok(total)
+}
+
+/// Appends the `WHERE` clause for `filters` and returns whether there was one.
+fn push_country_filters<'a>(qb: &mut QueryBuilder<'a, MySql>, filters: &'a CountryFilters) -> bool {
     let mut needs_where = true;
+    let mut push_condition = |qb: &mut QueryBuilder<'a, MySql>, sql: &str| {
+        qb.push(if needs_where { " WHERE " } else { " AND " });
+        qb.push(sql);
+        needs_where = false;
+    };
+
+    if !filters.include_deleted {
+        push_condition(qb, "deleted_at IS NULL");
+    }
 
-    if let Some(region) = query.region {
-        qb.push(" WHERE region = ");
+    if let Some(region) = &filters.region {
+        push_condition(qb, "region = ");
         qb.push_bind(region);
-        needs_where = false;
     }
 
-    if let Some(currency) = query.currency {
-        if needs_where {
-            qb.push(" WHERE currency_code = ");
-        } else {
-            qb.push(" AND currency_code = ");
-        }
+    if let Some(currency) = &filters.currency {
+        // Match any of the country's currencies, not just the primary one
+        push_condition(
+            qb,
+            "id IN (SELECT country_id FROM country_currencies WHERE currency_code = ",
+        );
         qb.push_bind(currency);
+        qb.push(")");
     }
 
-    if let Some(sort) = query.sort {
-        let sort_sql = match sort.as_str() {
-            "gdp_desc" => Some(" ORDER BY estimated_gdp DESC"),
-            "gdp_asc" => Some(" ORDER BY estimated_gdp ASC"),
-            "pop_desc" => Some(" ORDER BY population DESC"),
-            "pop_asc" => Some(" ORDER BY population ASC"),
-            "name_asc" => Some(" ORDER BY name ASC"),
-            "name_desc" => Some(" ORDER BY name DESC"),
-            _ => None, // Ignore invalid sort key
-        };
+    // Inclusive bounds; rows with a NULL value never match a bound on it
+    if let Some(min) = filters.min_population {
+        push_condition(qb, "population >= ");
+        qb.push_bind(min);
+    }
+    if let Some(max) = filters.max_population {
+        push_condition(qb, "population <= ");
+        qb.push_bind(max);
+    }
+    if let Some(min) = filters.min_gdp {
+        push_condition(qb, "estimated_gdp >= ");
+        qb.push_bind(min);
+    }
+    if let Some(max) = filters.max_gdp {
+        push_condition(qb, "estimated_gdp <= ");
+        qb.push_bind(max);
+    }
+    if let Some(min) = filters.min_rate {
+        push_condition(qb, "exchange_rate >= ");
+        qb.push_bind(min);
+    }
+    if let Some(max) = filters.max_rate {
+        push_condition(qb, "exchange_rate <= ");
+        qb.push_bind(max);
+    }
+    !needs_where
+}
 
-        if let Some(sql) = sort_sql {
-            qb.push(sql);
-        }
+/// Finds countries whose name, capital or region matches `q`, best first:
+/// exact matches, then prefix matches, then the rest. The rest are substring
+/// matches plus FULLTEXT matches on the prefixes of every word of `q`, which
+/// also finds names whose words appear in another order.
+pub async fn search_countries(
+    pool: &MySqlPool,
+    q: &str,
+    limit: i64,
+) -> Result<Vec<Country>, AppError> {
+    let prefix = format!("{}%", escape_like(q));
+    let substring = format!("%{}%", escape_like(q));
+    let boolean_query = fulltext_prefix_query(q);
+
+    let mut qb: QueryBuilder<MySql> =
+        QueryBuilder::new("SELECT * FROM countries WHERE deleted_at IS NULL AND ");
+    qb.push("(name LIKE ").push_bind(substring.clone());
+    qb.push(" OR capital LIKE ").push_bind(substring.clone());
+    qb.push(" OR region LIKE ").push_bind(substring);
+    if let Some(boolean_query) = &boolean_query {
+        qb.push(" OR MATCH (name, capital, region) AGAINST (")
+            .push_bind(boolean_query.clone())
+            .push(" IN BOOLEAN MODE)");
     }
-    let countries = qb.build_query_as().fetch_all(pool).await?;
+    qb.push(") ORDER BY CASE WHEN name = ").push_bind(q);
+    qb.push(" OR capital = ").push_bind(q);
+    qb.push(" OR region = ").push_bind(q);
+    qb.push(" THEN 0 WHEN name LIKE ").push_bind(prefix.clone());
+    qb.push(" OR capital LIKE ").push_bind(prefix.clone());
+    qb.push(" OR region LIKE ").push_bind(prefix.clone());
+    qb.push(" THEN 1 ELSE 2 END");
+    // Within a tier, matches on the name itself come before capital/region ones
+    qb.push(", name LIKE ").push_bind(prefix).push(" DESC");
+    if let Some(boolean_query) = boolean_query {
+        qb.push(", MATCH (name, capital, region) AGAINST (")
+            .push_bind(boolean_query)
+            .push(" IN BOOLEAN MODE) DESC");
+    }
+    qb.push(", name ASC, id ASC LIMIT ").push_bind(limit);
+
+    let mut countries = qb.build_query_as().fetch_all(pool).await?;
+    attach_currencies(pool, &mut countries).await?;
     Ok(countries)
 }
 
-/// Retrieves a single country by its name.
+/// Escapes the `LIKE` wildcards in `value`.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Turns `q` into a boolean-mode FULLTEXT query requiring a prefix match on
+/// every word (`+word*`). Operator characters split words, and words shorter
+/// than InnoDB's default minimum token size (3) are left to the `LIKE` match.
+/// `None` if nothing searchable is left.
+fn fulltext_prefix_query(q: &str) -> Option<String> {
+    let cleaned: String = q
+        .chars()
+        .map(|c| if "+-<>()~*\"@".contains(c) { ' ' } else { c })
+        .collect();
+    let terms: Vec<String> = cleaned
+        .split_whitespace()
+        .filter(|word| word.chars().count() >= 3)
+        .map(|word| format!("+{}*", word))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Retrieves a single country by its name or ISO alpha code.
 pub async fn get_country_by_name(
     pool: &MySqlPool,
     name: &str,
 ) -> Result<Country, AppError> {
-    sqlx::query_as::<_, Country>("SELECT * FROM countries WHERE name = ?")
-        .bind(name)
+    let id = match find_country_id(pool, name, false).await? {
+        Some(id) => id,
+        None => return Err(country_not_found(pool, name, false).await?),
+    };
+    get_country_by_id(pool, id).await
+}
+
+async fn get_country_by_id(pool: &MySqlPool, id: i32) -> Result<Country, AppError> {
+    let country = sqlx::query_as::<_, Country>("SELECT * FROM countries WHERE id = ?")
+        .bind(id)
         .fetch_one(pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => AppError::NotFound(format!("Country '{}' not found", name)),
-            _ => AppError::DatabaseError(e),
+        .await?;
+
+    let mut countries = vec![country];
+    attach_currencies(pool, &mut countries).await?;
+    Ok(countries.remove(0))
+}
+
+/// Resolves `name` like `get_country_by_name` and returns the country's id
+/// and stored data, aliases included: the base for a manual update.
+pub async fn get_country_record(
+    pool: &MySqlPool,
+    name: &str,
+) -> Result<(i32, CountryRecord), AppError> {
+    let country = get_country_by_name(pool, name).await?;
+    let aliases: Vec<(String,)> =
+        sqlx::query_as("SELECT alias FROM country_aliases WHERE country_id = ? ORDER BY position")
+            .bind(country.id)
+            .fetch_all(pool)
+            .await?;
+
+    let record = CountryRecord {
+        name: country.name,
+        capital: country.capital,
+        region: country.region,
+        population: country.population,
+        flag_url: country.flag_url,
+        currencies: country
+            .currencies
+            .into_iter()
+            .map(|c| CurrencyInfo {
+                code: c.code,
+                name: c.name,
+                symbol: c.symbol,
+            })
+            .collect(),
+        alt_spellings: aliases.into_iter().map(|(alias,)| alias).collect(),
+        alpha2_code: country.alpha2_code,
+        alpha3_code: country.alpha3_code,
+        numeric_code: country.numeric_code,
+    };
+    Ok((country.id, record))
+}
+
+/// Writes a country sent to `POST`, `PUT` or `PATCH /countries` and marks it
+/// as a manual override. Inserts it when `id` is `None`, otherwise replaces
+/// the country with that id. Exchange rate and GDP are based on the latest
+/// stored rate of the primary currency.
+pub async fn save_manual_country(
+    pool: &MySqlPool,
+    id: Option<i32>,
+    country: CountryRecord,
+    estimator: &dyn GdpEstimator,
+) -> Result<Country, AppError> {
+    let mut tx = pool.begin().await?;
+    let now = Utc::now().trunc_subsecs(0);
+    let rate = match country.currencies.first() {
+        Some(currency) => latest_rate(&mut tx, &currency.code)
+            .await?
+            .and_then(|point| f64::try_from(point.rate).ok()),
+        None => None,
+    };
+    let fields = country_fields(&country, rate, estimator, true);
+
+    // Plain INSERT/UPDATE so a name or ISO code that another country holds
+    // fails on its unique index instead of overwriting that country
+    let id = match id {
+        None => insert_country(&mut tx, &fields, now)
+            .await
+            .map_err(unique_conflict)?,
+        Some(id) => {
+            update_country(&mut tx, id, &fields, now)
+                .await
+                .map_err(unique_conflict)?;
+            id
+        }
+    };
+
+    let aliases = names::aliases(&fields.name, &country.alt_spellings);
+    let currency_updates = HashMap::from([(fields.name.clone(), country.currencies)]);
+    let alias_updates = HashMap::from([(fields.name.clone(), aliases)]);
+    replace_currencies(&mut tx, &currency_updates, 1).await?;
+    replace_aliases(&mut tx, &alias_updates, 1).await?;
+
+    update_total_countries(&mut tx).await?;
+    tx.commit().await?;
+
+    get_country_by_id(pool, id).await
+}
+
+/// Inserts a single country and returns its id.
+async fn insert_country(
+    conn: &mut MySqlConnection,
+    fields: &CountryFields,
+    refreshed_at: DateTime<Utc>,
+) -> Result<i32, AppError> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO countries (
+            name, name_normalized, alpha2_code, alpha3_code, numeric_code, capital, region,
+            population, currency_code, exchange_rate, estimated_gdp, flag_url, last_refreshed_at,
+            manual_override
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&fields.name)
+    .bind(names::normalize(&fields.name))
+    .bind(&fields.alpha2_code)
+    .bind(&fields.alpha3_code)
+    .bind(&fields.numeric_code)
+    .bind(&fields.capital)
+    .bind(&fields.region)
+    .bind(fields.population)
+    .bind(&fields.currency_code)
+    .bind(fields.exchange_rate)
+    .bind(fields.estimated_gdp)
+    .bind(&fields.flag_url)
+    .bind(refreshed_at)
+    .bind(fields.manual_override)
+    .execute(&mut *conn)
+    .await?;
+    Ok(result.last_insert_id() as i32)
+}
+
+/// Overwrites every stored column of the country `id` with `fields`.
+async fn update_country(
+    conn: &mut MySqlConnection,
+    id: i32,
+    fields: &CountryFields,
+    refreshed_at: DateTime<Utc>,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        UPDATE countries
+        SET name = ?, name_normalized = ?, alpha2_code = ?, alpha3_code = ?, numeric_code = ?,
+            capital = ?, region = ?, population = ?, currency_code = ?, exchange_rate = ?,
+            estimated_gdp = ?, flag_url = ?, last_refreshed_at = ?, manual_override = ?,
+            stale_since = NULL
+        WHERE id = ?
+        "#,
+    )
+    .bind(&fields.name)
+    .bind(names::normalize(&fields.name))
+    .bind(&fields.alpha2_code)
+    .bind(&fields.alpha3_code)
+    .bind(&fields.numeric_code)
+    .bind(&fields.capital)
+    .bind(&fields.region)
+    .bind(fields.population)
+    .bind(&fields.currency_code)
+    .bind(fields.exchange_rate)
+    .bind(fields.estimated_gdp)
+    .bind(&fields.flag_url)
+    .bind(refreshed_at)
+    .bind(fields.manual_override)
+    .bind(id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// The most recently stored USD rate of `code`, if any. Zero rates, which
+/// nothing can be converted with, are ignored.
+async fn latest_rate(
+    conn: &mut MySqlConnection,
+    code: &str,
+) -> Result<Option<RateHistoryPoint>, AppError> {
+    let point = sqlx::query_as::<_, RateHistoryPoint>(
+        r#"
+        SELECT rate, fetched_at, refresh_run, provider FROM exchange_rate_history
+        WHERE currency_code = ? AND rate > 0
+        ORDER BY fetched_at DESC, id DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(code)
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(point)
+}
+
+/// Reports a unique-key violation on a manual write as a 409 naming the
+/// column; anything else stays a database error.
+fn unique_conflict(e: AppError) -> AppError {
+    const KEYS: &[(&str, &str)] = &[
+        ("uq_countries_alpha2", "alpha2_code"),
+        ("uq_countries_alpha3", "alpha3_code"),
+        ("uq_countries_numeric", "numeric_code"),
+    ];
+    match &e {
+        AppError::DatabaseError(sqlx::Error::Database(db))
+            if db.kind() == sqlx::error::ErrorKind::UniqueViolation =>
+        {
+            let column = KEYS
+                .iter()
+                .find(|(key, _)| db.message().contains(key))
+                .map_or("name", |(_, column)| column);
+            AppError::Conflict(format!("Another country already has this {}", column))
+        }
+        _ => e,
+    }
+}
+
+/// Resolves each of `names` like `get_country_by_name` and returns the
+/// countries in that order with their `ComparisonMetrics`. A country named
+/// twice is listed once. Fails with every name that matched nothing.
+pub async fn compare_countries(
+    pool: &MySqlPool,
+    names: &[String],
+) -> Result<Vec<CountryComparison>, AppError> {
+    let mut ids = Vec::with_capacity(names.len());
+    let mut missing = Vec::new();
+    for name in names {
+        match find_country_id(pool, name, false).await? {
+            Some(id) if !ids.contains(&id) => ids.push(id),
+            Some(_) => {}
+            None => missing.push(name.clone()),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(AppError::CountriesNotFound(missing));
+    }
+
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new("SELECT * FROM countries WHERE id IN (");
+    let mut separated = qb.separated(", ");
+    for id in &ids {
+        separated.push_bind(*id);
+    }
+    qb.push(")");
+    let mut countries: Vec<Country> = qb.build_query_as().fetch_all(pool).await?;
+    attach_currencies(pool, &mut countries).await?;
+
+    // A country's rank is one more than the number of countries ahead of it
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        r#"
+        SELECT c.id,
+               CASE WHEN c.estimated_gdp IS NULL THEN NULL ELSE 1 + (
+                   SELECT COUNT(*) FROM countries o
+                   WHERE o.deleted_at IS NULL AND o.estimated_gdp > c.estimated_gdp
+               ) END AS gdp_rank,
+               1 + (
+                   SELECT COUNT(*) FROM countries o
+                   WHERE o.deleted_at IS NULL AND o.population > c.population
+               ) AS population_rank,
+               (
+                   SELECT CAST(SUM(o.population) AS SIGNED) FROM countries o
+                   WHERE o.deleted_at IS NULL AND o.region = c.region
+               ) AS region_population
+        FROM countries c
+        WHERE c.id IN (
+        "#,
+    );
+    let mut separated = qb.separated(", ");
+    for id in &ids {
+        separated.push_bind(*id);
+    }
+    qb.push(")");
+    let mut ranks: HashMap<i32, (Option<i64>, i64, Option<i64>)> = qb
+        .build_query_as::<(i32, Option<i64>, i64, Option<i64>)>()
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(id, gdp_rank, population_rank, region_population)| {
+            (id, (gdp_rank, population_rank, region_population))
+        })
+        .collect();
+
+    let mut by_id: HashMap<i32, Country> = countries.into_iter().map(|c| (c.id, c)).collect();
+    Ok(ids
+        .iter()
+        .filter_map(|id| {
+            let country = by_id.remove(id)?;
+            let (gdp_rank, population_rank, region_population) = ranks.remove(id)?;
+            let population = Decimal::from(country.population);
+            let metrics = ComparisonMetrics {
+                gdp_per_capita: country
+                    .estimated_gdp
+                    .and_then(|gdp| gdp.checked_div(population))
+                    .map(|value| value.round_dp(6)),
+                region_population_share: region_population
+                    .and_then(|total| population.checked_div(Decimal::from(total)))
+                    .map(|share| share.round_dp(6)),
+                gdp_rank,
+                population_rank,
+            };
+            Some(CountryComparison { country, metrics })
         })
+        .collect())
 }
 
-/// Deletes a single country by its name.
+/// Soft-deletes a single country by its name or ISO alpha code. The row stays
+/// behind as a tombstone so that refreshes don't bring the country back.
 pub async fn delete_country_by_name(
     pool: &MySqlPool,
     name: &str,
 ) -> Result<(), AppError> {
-    let result = sqlx::query("DELETE FROM countries WHERE name = ?")
-        .bind(name)
-        .execute(pool)
+    let id = match find_country_id(pool, name, false).await? {
+        Some(id) => id,
+        None => return Err(country_not_found(pool, name, false).await?),
+    };
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE countries SET deleted_at = ? WHERE id = ?")
+        .bind(Utc::now())
+        .bind(id)
+        .execute(&mut *tx)
         .await?;
+    update_total_countries(&mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
 
-    if result.rows_affected() == 0 {
-        Err(AppError::NotFound(format!("Country '{}' not found", name)))
-    } else {
-        Ok(())
-    }
+/// Undoes `delete_country_by_name`. Restoring a country that isn't deleted
+/// changes nothing. Its data is as old as the deletion until the next refresh.
+pub async fn restore_country_by_name(pool: &MySqlPool, name: &str) -> Result<Country, AppError> {
+    let id = match find_country_id(pool, name, true).await? {
+        Some(id) => id,
+        None => return Err(country_not_found(pool, name, true).await?),
+    };
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE countries SET deleted_at = NULL WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    update_total_countries(&mut tx).await?;
+    tx.commit().await?;
+    get_country_by_id(pool, id).await
+}
+
+/// Recounts `app_status.total_countries` after a change outside a refresh.
+async fn update_total_countries(conn: &mut MySqlConnection) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        UPDATE app_status
+        SET total_countries = (
+            SELECT COUNT(*) FROM countries WHERE stale_since IS NULL AND deleted_at IS NULL
+        )
+        WHERE id = 1
+        "#,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Resolves `name` to a country id: the exact name first, then an ISO
+/// alpha-2 or alpha-3 code, then the accent- and case-folded name, then an alias.
+/// Deleted countries only match with `include_deleted`.
+async fn find_country_id(
+    pool: &MySqlPool,
+    name: &str,
+    include_deleted: bool,
+) -> Result<Option<i32>, AppError> {
+    let normalized = names::normalize(name);
+    let code = name.trim().to_uppercase();
+    let id: Option<(i32,)> = sqlx::query_as(
+        r#"
+        SELECT id FROM countries
+        WHERE (deleted_at IS NULL OR ?)
+          AND (name = ? OR alpha2_code = ? OR alpha3_code = ? OR name_normalized = ?
+               OR id IN (SELECT country_id FROM country_aliases WHERE alias_normalized = ?))
+        ORDER BY name = ? DESC, (alpha2_code <=> ? OR alpha3_code <=> ?) DESC,
+                 name_normalized = ? DESC, id
+        LIMIT 1
+        "#,
+    )
+    .bind(include_deleted)
+    .bind(name)
+    .bind(&code)
+    .bind(&code)
+    .bind(&normalized)
+    .bind(&normalized)
+    .bind(name)
+    .bind(&code)
+    .bind(&code)
+    .bind(&normalized)
+    .fetch_optional(pool)
+    .await?;
+    Ok(id.map(|(id,)| id))
+}
+
+/// The 404 for a failed lookup of `name`, with the closest stored names.
+async fn country_not_found(
+    pool: &MySqlPool,
+    name: &str,
+    include_deleted: bool,
+) -> Result<AppError, AppError> {
+    let candidates: Vec<(String, String)> = sqlx::query_as(
+        r#"
+        SELECT name, name_normalized FROM countries WHERE deleted_at IS NULL OR ?
+        UNION ALL
+        SELECT c.name, a.alias_normalized
+        FROM country_aliases a
+        JOIN countries c ON c.id = a.country_id
+        WHERE c.deleted_at IS NULL OR ?
+        "#,
+    )
+    .bind(include_deleted)
+    .bind(include_deleted)
+    .fetch_all(pool)
+    .await?;
+    Ok(AppError::CountryNotFound {
+        name: name.to_string(),
+        suggestions: names::suggest(name, &candidates),
+    })
 }
 
 /// Gets the global application status.
 pub async fn get_app_status(pool: &MySqlPool) -> Result<AppStatus, AppError> {
     let status = sqlx::query_as::<_, AppStatus>(
-        "SELECT total_countries, last_refreshed_at FROM app_status WHERE id = 1",
+        r#"
+        SELECT total_countries, last_refreshed_at, rate_provider, gdp_strategy, gdp_seed
+        FROM app_status WHERE id = 1
+        "#,
     )
     .fetch_one(pool)
     .await?;
     Ok(status)
-}
\ No newline at end of file
+}
+
+/// Writes one multi-row `INSERT ... ON DUPLICATE KEY UPDATE` for `rows`.
+async fn upsert_countries(
+    conn: &mut MySqlConnection,
+    rows: &[CountryFields],
+    refresh_time: DateTime<Utc>,
+) -> Result<(), AppError> {
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        r#"
+        INSERT INTO countries (
+            name, name_normalized, alpha2_code, alpha3_code, numeric_code, capital, region,
+            population, currency_code, exchange_rate, estimated_gdp, flag_url, last_refreshed_at,
+            manual_override
+        )
+        "#,
+    );
+    qb.push_values(rows, |mut b, fields| {
+        b.push_bind(&fields.name)
+            .push_bind(names::normalize(&fields.name))
+            .push_bind(&fields.alpha2_code)
+            .push_bind(&fields.alpha3_code)
+            .push_bind(&fields.numeric_code)
+            .push_bind(&fields.capital)
+            .push_bind(&fields.region)
+            .push_bind(fields.population)
+            .push_bind(&fields.currency_code)
+            .push_bind(fields.exchange_rate)
+            .push_bind(fields.estimated_gdp)
+            .push_bind(&fields.flag_url)
+            .push_bind(refresh_time)
+            .push_bind(fields.manual_override);
+    });
+    qb.push(
+        r#"
+        ON DUPLICATE KEY UPDATE
+            name = VALUES(name),
+            name_normalized = VALUES(name_normalized),
+            alpha2_code = VALUES(alpha2_code),
+            alpha3_code = VALUES(alpha3_code),
+            numeric_code = VALUES(numeric_code),
+            capital = VALUES(capital),
+            region = VALUES(region),
+            population = VALUES(population),
+            currency_code = VALUES(currency_code),
+            exchange_rate = VALUES(exchange_rate),
+            estimated_gdp = VALUES(estimated_gdp),
+            flag_url = VALUES(flag_url),
+            last_refreshed_at = VALUES(last_refreshed_at),
+            manual_override = VALUES(manual_override),
+            stale_since = NULL
+        "#,
+    );
+    qb.build().execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// Replaces the `country_currencies` rows of every country in `updates`,
+/// keyed by country name, `batch_size` countries at a time.
+async fn replace_currencies(
+    conn: &mut MySqlConnection,
+    updates: &HashMap<String, Vec<CurrencyInfo>>,
+    batch_size: usize,
+) -> Result<(), AppError> {
+    let mut country_names: Vec<&String> = updates.keys().collect();
+    country_names.sort();
+
+    for chunk in country_names.chunks(batch_size) {
+        let ids = lookup_country_ids(conn, chunk).await?;
+        let mut rows = Vec::new();
+        for name in chunk {
+            let Some(&country_id) = ids.get(&names::collation_key(name)) else {
+                continue;
+            };
+            for (position, currency) in unique_currencies(&updates[*name]).into_iter().enumerate() {
+                rows.push((country_id, position as i32, currency));
+            }
+        }
+        delete_country_rows(conn, "country_currencies", ids.values()).await?;
+
+        if rows.is_empty() {
+            continue;
+        }
+        let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+            "INSERT INTO country_currencies (country_id, currency_code, name, symbol, position) ",
+        );
+        qb.push_values(rows, |mut b, (country_id, position, currency)| {
+            b.push_bind(country_id)
+                .push_bind(&currency.code)
+                .push_bind(&currency.name)
+                .push_bind(&currency.symbol)
+                .push_bind(position);
+        });
+        qb.build().execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Replaces the `country_aliases` rows of every country in `updates`,
+/// keyed by country name, `batch_size` countries at a time.
+async fn replace_aliases(
+    conn: &mut MySqlConnection,
+    updates: &HashMap<String, Vec<String>>,
+    batch_size: usize,
+) -> Result<(), AppError> {
+    let mut country_names: Vec<&String> = updates.keys().collect();
+    country_names.sort();
+
+    for chunk in country_names.chunks(batch_size) {
+        let ids = lookup_country_ids(conn, chunk).await?;
+        let mut rows = Vec::new();
+        for name in chunk {
+            let Some(&country_id) = ids.get(&names::collation_key(name)) else {
+                continue;
+            };
+            for (position, alias) in updates[*name].iter().enumerate() {
+                rows.push((country_id, position as i32, alias));
+            }
+        }
+        delete_country_rows(conn, "country_aliases", ids.values()).await?;
+
+        if rows.is_empty() {
+            continue;
+        }
+        let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+            "INSERT INTO country_aliases (country_id, alias, alias_normalized, position) ",
+        );
+        qb.push_values(rows, |mut b, (country_id, position, alias)| {
+            b.push_bind(country_id)
+                .push_bind(alias)
+                .push_bind(names::normalize(alias))
+                .push_bind(position);
+        });
+        qb.build().execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Looks up the ids of the countries called `names`. A multi-row upsert
+/// doesn't report them. Keyed by `names::collation_key`, since `name`
+/// compares ignoring case and accents.
+async fn lookup_country_ids(
+    conn: &mut MySqlConnection,
+    names: &[&String],
+) -> Result<HashMap<String, i32>, AppError> {
+    let mut qb: QueryBuilder<MySql> =
+        QueryBuilder::new("SELECT id, name FROM countries WHERE name IN (");
+    let mut separated = qb.separated(", ");
+    for name in names {
+        separated.push_bind(*name);
+    }
+    qb.push(")");
+    Ok(qb
+        .build_query_as::<(i32, String)>()
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(id, name)| (names::collation_key(&name), id))
+        .collect())
+}
+
+/// Deletes the rows of a per-country child table (`country_currencies`,
+/// `country_aliases`) that belong to `country_ids`.
+async fn delete_country_rows(
+    conn: &mut MySqlConnection,
+    table: &'static str,
+    country_ids: impl IntoIterator<Item = &i32>,
+) -> Result<(), AppError> {
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(format!("DELETE FROM {} WHERE country_id IN (", table));
+    let mut any = false;
+    let mut separated = qb.separated(", ");
+    for country_id in country_ids {
+        separated.push_bind(*country_id);
+        any = true;
+    }
+    qb.push(")");
+    if any {
+        qb.build().execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Loads the full currency list for each of `countries` and fills in `primary_currency`.
+async fn attach_currencies(pool: &MySqlPool, countries: &mut [Country]) -> Result<(), AppError> {
+    if countries.is_empty() {
+        return Ok(());
+    }
+
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        "SELECT country_id, currency_code, name, symbol FROM country_currencies WHERE country_id IN (",
+    );
+    let mut ids = qb.separated(", ");
+    for country in countries.iter() {
+        ids.push_bind(country.id);
+    }
+    qb.push(") ORDER BY country_id, position");
+
+    let rows: Vec<(i32, String, Option<String>, Option<String>)> =
+        qb.build_query_as().fetch_all(pool).await?;
+    let mut by_country: HashMap<i32, Vec<CountryCurrency>> = HashMap::new();
+    for (country_id, code, name, symbol) in rows {
+        by_country
+            .entry(country_id)
+            .or_default()
+            .push(CountryCurrency { code, name, symbol });
+    }
+    for country in countries.iter_mut() {
+        country.currencies = by_country.remove(&country.id).unwrap_or_default();
+        country.primary_currency = country.currency_code.clone();
+    }
+    Ok(())
+}
+
+/// Records the start of a refresh run and returns its id.
+pub async fn start_refresh_run(
+    pool: &MySqlPool,
+    started_at: DateTime<Utc>,
+) -> Result<i32, AppError> {
+    let result = sqlx::query("INSERT INTO refresh_runs (status, started_at) VALUES ('running', ?)")
+        .bind(started_at)
+        .execute(pool)
+        .await?;
+    Ok(result.last_insert_id() as i32)
+}
+
+/// Records how a refresh run ended.
+pub async fn finish_refresh_run(
+    pool: &MySqlPool,
+    id: i32,
+    summary: &RefreshRunSummary,
+) -> Result<(), AppError> {
+    let status = if summary.failure_reason.is_some() {
+        "failed"
+    } else {
+        "success"
+    };
+    sqlx::query(
+        r#"
+        UPDATE refresh_runs
+        SET status = ?, finished_at = ?, duration_ms = ?,
+            countries_inserted = ?, countries_updated = ?,
+            countries_unchanged = ?, countries_removed = ?, countries_skipped = ?,
+            country_provider = ?, rate_provider = ?, failure_reason = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(status)
+    .bind(summary.finished_at)
+    .bind(summary.duration_ms)
+    .bind(summary.stats.inserted)
+    .bind(summary.stats.updated)
+    .bind(summary.stats.unchanged)
+    .bind(summary.stats.removed)
+    .bind(summary.stats.skipped)
+    .bind(&summary.country_provider)
+    .bind(&summary.rate_provider)
+    .bind(&summary.failure_reason)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Lists refresh runs, newest first.
+pub async fn get_refresh_runs(
+    pool: &MySqlPool,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<RefreshRun>, AppError> {
+    let runs = sqlx::query_as::<_, RefreshRun>(
+        "SELECT * FROM refresh_runs ORDER BY id DESC LIMIT ? OFFSET ?",
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    Ok(runs)
+}
+
+/// Retrieves a single refresh run by id.
+pub async fn get_refresh_run(pool: &MySqlPool, id: i32) -> Result<RefreshRun, AppError> {
+    sqlx::query_as::<_, RefreshRun>("SELECT * FROM refresh_runs WHERE id = ?")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => AppError::NotFound(format!("Refresh run {} not found", id)),
+            _ => AppError::DatabaseError(e),
+        })
+}
+
+/// Summarises every currency used by a country, or just `code`, ordered by
+/// code. Deleted countries don't count.
+pub async fn get_currencies(
+    pool: &MySqlPool,
+    code: Option<&str>,
+) -> Result<Vec<CurrencySummary>, AppError> {
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        r#"
+        SELECT cc.currency_code AS code, MAX(cc.name) AS name, MAX(cc.symbol) AS symbol,
+               COUNT(*) AS country_count,
+               CAST(SUM(c.population) AS SIGNED) AS total_population,
+               SUM(c.estimated_gdp) AS total_estimated_gdp
+        FROM country_currencies cc
+        JOIN countries c ON c.id = cc.country_id
+        WHERE c.deleted_at IS NULL
+        "#,
+    );
+    if let Some(code) = code {
+        qb.push(" AND cc.currency_code = ").push_bind(code);
+    }
+    qb.push(" GROUP BY cc.currency_code ORDER BY cc.currency_code");
+    let mut currencies: Vec<CurrencySummary> = qb.build_query_as().fetch_all(pool).await?;
+    if currencies.is_empty() {
+        return Ok(currencies);
+    }
+
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        r#"
+        SELECT cc.currency_code, c.name
+        FROM country_currencies cc
+        JOIN countries c ON c.id = cc.country_id
+        WHERE c.deleted_at IS NULL
+        "#,
+    );
+    if let Some(code) = code {
+        qb.push(" AND cc.currency_code = ").push_bind(code);
+    }
+    qb.push(" ORDER BY c.name");
+    let rows: Vec<(String, String)> = qb.build_query_as().fetch_all(pool).await?;
+    let mut countries: HashMap<String, Vec<String>> = HashMap::new();
+    for (code, name) in rows {
+        countries.entry(code).or_default().push(name);
+    }
+
+    // Latest rate per currency; ties on `fetched_at` go to the later row
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        r#"
+        SELECT h.currency_code, h.rate, h.fetched_at
+        FROM exchange_rate_history h
+        JOIN (
+            SELECT currency_code, MAX(fetched_at) AS fetched_at
+            FROM exchange_rate_history
+            GROUP BY currency_code
+        ) latest ON latest.currency_code = h.currency_code AND latest.fetched_at = h.fetched_at
+        "#,
+    );
+    if let Some(code) = code {
+        qb.push(" WHERE h.currency_code = ").push_bind(code);
+    }
+    qb.push(" ORDER BY h.id");
+    let rows: Vec<(String, Decimal, DateTime<Utc>)> = qb.build_query_as().fetch_all(pool).await?;
+    let rates: HashMap<String, (Decimal, DateTime<Utc>)> = rows
+        .into_iter()
+        .map(|(code, rate, fetched_at)| (code, (rate, fetched_at)))
+        .collect();
+
+    for currency in currencies.iter_mut() {
+        currency.countries = countries.remove(&currency.code).unwrap_or_default();
+        if let Some(&(rate, fetched_at)) = rates.get(&currency.code) {
+            currency.usd_rate = Some(rate);
+            currency.rate_fetched_at = Some(fetched_at);
+        }
+    }
+    Ok(currencies)
+}
+
+/// Summarises every region, or just `region`, ordered by name. Countries
+/// without a region and deleted countries don't count.
+pub async fn get_regions(
+    pool: &MySqlPool,
+    region: Option<&str>,
+) -> Result<Vec<RegionSummary>, AppError> {
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        r#"
+        SELECT region, COUNT(*) AS country_count,
+               CAST(SUM(population) AS SIGNED) AS total_population,
+               SUM(estimated_gdp) AS total_estimated_gdp,
+               AVG(estimated_gdp) AS mean_estimated_gdp,
+               CAST(SUM(exchange_rate IS NULL) AS SIGNED) AS missing_rate_count
+        FROM countries
+        WHERE deleted_at IS NULL AND region IS NOT NULL
+        "#,
+    );
+    if let Some(region) = region {
+        qb.push(" AND region = ").push_bind(region);
+    }
+    qb.push(" GROUP BY region ORDER BY region");
+    let mut regions: Vec<RegionSummary> = qb.build_query_as().fetch_all(pool).await?;
+    if regions.is_empty() {
+        return Ok(regions);
+    }
+
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        r#"
+        SELECT c.region, COUNT(DISTINCT cc.currency_code)
+        FROM countries c
+        JOIN country_currencies cc ON cc.country_id = c.id
+        WHERE c.deleted_at IS NULL AND c.region IS NOT NULL
+        "#,
+    );
+    if let Some(region) = region {
+        qb.push(" AND c.region = ").push_bind(region);
+    }
+    qb.push(" GROUP BY c.region");
+    // Keyed by lowercased region, since `region` groups case-insensitively
+    let currency_counts: HashMap<String, i64> = qb
+        .build_query_as::<(String, i64)>()
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(region, count)| (region.to_lowercase(), count))
+        .collect();
+
+    // MySQL has no MEDIAN(); sort the populations here instead
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        "SELECT region, population FROM countries WHERE deleted_at IS NULL AND region IS NOT NULL",
+    );
+    if let Some(region) = region {
+        qb.push(" AND region = ").push_bind(region);
+    }
+    qb.push(" ORDER BY population");
+    let mut populations: HashMap<String, Vec<i64>> = HashMap::new();
+    for (region, population) in qb.build_query_as::<(String, i64)>().fetch_all(pool).await? {
+        populations.entry(region.to_lowercase()).or_default().push(population);
+    }
+
+    for summary in regions.iter_mut() {
+        let key = summary.region.to_lowercase();
+        summary.mean_estimated_gdp = summary.mean_estimated_gdp.map(|mean| mean.round_dp(6));
+        summary.currency_count = currency_counts.get(&key).copied().unwrap_or(0);
+        summary.median_population = median(populations.get(&key).map_or(&[], Vec::as_slice));
+    }
+    Ok(regions)
+}
+
+/// The median of ascending `values`; `0` when there are none.
+fn median(values: &[i64]) -> f64 {
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => 0.0,
+        len if len % 2 == 1 => values[mid] as f64,
+        _ => (values[mid - 1] as f64 + values[mid] as f64) / 2.0,
+    }
+}
+
+/// Returns the latest stored USD rate of one currency.
+pub async fn get_latest_rate(pool: &MySqlPool, code: &str) -> Result<RateHistoryPoint, AppError> {
+    let mut conn = pool.acquire().await?;
+    latest_rate(&mut conn, code)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("No exchange rate for currency '{}'", code)))
+}
+
+/// Returns the stored rates of one currency within an optional time range, oldest first.
+pub async fn get_rate_history(
+    pool: &MySqlPool,
+    code: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Vec<RateHistoryPoint>, AppError> {
+    let mut qb: QueryBuilder<MySql> = QueryBuilder::new(
+        "SELECT rate, fetched_at, refresh_run, provider FROM exchange_rate_history WHERE currency_code = ",
+    );
+    qb.push_bind(code);
+    if let Some(from) = from {
+        qb.push(" AND fetched_at >= ");
+        qb.push_bind(from);
+    }
+    if let Some(to) = to {
+        qb.push(" AND fetched_at <= ");
+        qb.push_bind(to);
+    }
+    qb.push(" ORDER BY fetched_at ASC, id ASC");
+
+    let points: Vec<RateHistoryPoint> = qb.build_query_as().fetch_all(pool).await?;
+    if points.is_empty() {
+        let known: Option<(i64,)> =
+            sqlx::query_as("SELECT 1 FROM exchange_rate_history WHERE currency_code = ? LIMIT 1")
+                .bind(code)
+                .fetch_optional(pool)
+                .await?;
+        if known.is_none() {
+            return Err(AppError::NotFound(format!(
+                "No exchange rate history for currency '{}'",
+                code
+            )));
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyset_sql(sort: &[SortKey], backwards: bool, values: Vec<Option<&str>>) -> String {
+        let position = Keyset {
+            values: values.into_iter().map(|v| v.map(str::to_string)).collect(),
+            id: 7,
+        };
+        let mut qb: QueryBuilder<MySql> = QueryBuilder::new("");
+        push_keyset_condition(&mut qb, &sort_order(sort, backwards), &position).unwrap();
+        qb.sql().to_string()
+    }
+
+    #[test]
+    fn keyset_condition_after_a_row() {
+        let sort = [
+            SortKey { column: "region", descending: false },
+            SortKey { column: "estimated_gdp", descending: true },
+        ];
+        assert_eq!(
+            keyset_sql(&sort, false, vec![Some("Africa"), Some("12.5")]),
+            "((region > ?) OR (region = ? AND (estimated_gdp < ? OR estimated_gdp IS NULL)) \
+             OR (region = ? AND estimated_gdp = ? AND id > ?))"
+        );
+    }
+
+    #[test]
+    fn keyset_condition_with_nulls() {
+        let sort = [SortKey { column: "capital", descending: false }];
+        assert_eq!(
+            keyset_sql(&sort, false, vec![None]),
+            "((capital IS NOT NULL) OR (capital IS NULL AND id > ?))"
+        );
+        // Read backwards the column is descending, and nothing comes after NULL
+        assert_eq!(
+            keyset_sql(&sort, true, vec![None]),
+            "((FALSE) OR (capital IS NULL AND (id < ? OR id IS NULL)))"
+        );
+    }
+
+    #[test]
+    fn keyset_condition_rejects_mismatched_cursors() {
+        let sort = [SortKey { column: "population", descending: false }];
+        let order = sort_order(&sort, false);
+        let mut qb: QueryBuilder<MySql> = QueryBuilder::new("");
+        let wrong_length = Keyset { values: vec![], id: 1 };
+        assert!(push_keyset_condition(&mut qb, &order, &wrong_length).is_err());
+        let not_a_number = Keyset { values: vec![Some("many".to_string())], id: 1 };
+        assert!(push_keyset_condition(&mut qb, &order, &not_a_number).is_err());
+    }
+}
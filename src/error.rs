@@ -1,4 +1,7 @@
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
 use serde_json::json;
 use std::collections::HashMap;
 use thiserror::Error;
@@ -8,15 +11,36 @@ pub enum AppError {
     #[error("Country not found: {0}")]
     NotFound(String),
 
-    #[allow(dead_code)]
+    #[error("Country not found: {name}")]
+    CountryNotFound {
+        name: String,
+        suggestions: Vec<String>,
+    },
+
+    #[error("Countries not found: {}", .0.join(", "))]
+    CountriesNotFound(Vec<String>),
+
     #[error("Validation failed")]
     ValidationError(HashMap<String, String>),
 
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("External data source unavailable: {api_name}")]
     ApiError {
         #[source]
         source: reqwest::Error,
         api_name: String,
+        attempts: u32,
+    },
+
+    #[error("Invalid payload from {api_name}: {details}")]
+    InvalidPayload { api_name: String, details: String },
+
+    #[error("Circuit open for {api_name}")]
+    CircuitOpen {
+        api_name: String,
+        retry_after_secs: u64,
     },
 
     #[error("Database error")]
@@ -29,12 +53,33 @@ pub enum AppError {
     Internal(#[from] anyhow::Error),
 }
 
+impl AppError {
+    /// The error followed by its chain of sources, on one line.
+    pub fn report(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(err) = source {
+            message.push_str(": ");
+            message.push_str(&err.to_string());
+            source = err.source();
+        }
+        message
+    }
+}
+
 impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self {
-            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::NotFound(_)
+            | AppError::CountryNotFound { .. }
+            | AppError::CountriesNotFound(_) => StatusCode::NOT_FOUND,
             AppError::ValidationError(_) => StatusCode::BAD_REQUEST,
-            AppError::ApiError { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::ApiError { .. }
+            | AppError::InvalidPayload { .. }
+            | AppError::CircuitOpen { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             AppError::DatabaseError(_) | AppError::Internal(_) | AppError::ImageError(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
@@ -47,13 +92,33 @@ impl ResponseError for AppError {
                 self.status_code(),
                 json!({ "error": message }),
             ),
+            AppError::CountryNotFound { name, suggestions } => (
+                self.status_code(),
+                json!({ "error": format!("Country '{}' not found", name), "suggestions": suggestions }),
+            ),
+            AppError::CountriesNotFound(names) => (
+                self.status_code(),
+                json!({ "error": "Countries not found", "missing": names }),
+            ),
             AppError::ValidationError(details) => (
                 self.status_code(),
                 json!({ "error": "Validation failed", "details": details }),
             ),
-            AppError::ApiError { source, api_name } => (
+            AppError::Conflict(message) => (
+                self.status_code(),
+                json!({ "error": message }),
+            ),
+            AppError::ApiError { source, api_name, attempts } => (
+                self.status_code(),
+                json!({ "error": "External data source unavailable", "details": format!("Could not fetch data from {} after {} attempt(s): {}", api_name, attempts, source) }),
+            ),
+            AppError::InvalidPayload { api_name, details } => (
                 self.status_code(),
-                json!({ "error": "External data source unavailable", "details": format!("Could not fetch data from {}: {}", api_name, source) }),
+                json!({ "error": "External data source unavailable", "details": format!("Could not parse data from {}: {}", api_name, details) }),
+            ),
+            AppError::CircuitOpen { api_name, retry_after_secs } => (
+                self.status_code(),
+                json!({ "error": "External data source unavailable", "details": format!("{} is failing; circuit breaker open, retry in {}s", api_name, retry_after_secs) }),
             ),
             AppError::DatabaseError(e) => {
                 log::error!("Database error: {:?}", e);
@@ -77,6 +142,10 @@ impl ResponseError for AppError {
                 )
             }
         };
-        HttpResponse::build(status).json(body)
+        let mut builder = HttpResponse::build(status);
+        if let AppError::CircuitOpen { retry_after_secs, .. } = self {
+            builder.insert_header((header::RETRY_AFTER, retry_after_secs.to_string()));
+        }
+        builder.json(body)
     }
 }
\ No newline at end of file
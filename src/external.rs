@@ -1,45 +1,1036 @@
+use crate::cache::{UpstreamCache, Validators};
+use crate::config::{Config, CountriesSchema, DataSource, RateProvider};
 use crate::error::AppError;
-use crate::models::{ExchangeRateResponse, RestCountryResponse};
+use crate::models::{
+    BreakerSnapshot, BreakerState, CountryRecord, ExchangeRateResponse, FetchedCountries,
+    FetchedRates,
+    RestCountryResponse, RestCountryV3Response,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use quick_xml::events::Event;
+use rand::Rng;
+use reqwest::{header, StatusCode};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const COUNTRIES_API_URL: &str = "https://restcountries.com/v2/all?fields=name,capital,region,population,flag,currencies";
+const COUNTRIES_API_URL: &str = "https://restcountries.com/v2/all?fields=name,capital,region,population,flag,currencies,altSpellings,alpha2Code,alpha3Code,numericCode";
+const COUNTRIES_V3_API_URL: &str = "https://restcountries.com/v3.1/all?fields=name,capital,region,population,flags,currencies,altSpellings,cca2,cca3,ccn3";
 const EXCHANGE_RATE_API_URL: &str = "https://open.er-api.com/v6/latest/USD";
+const ECB_RATES_URL: &str = "https://www.ecb.europa.eu/stats/eurofxref/eurofxref-daily.xml";
 
-/// Fetches all country data from the RestCountries API.
-pub async fn fetch_countries(
-    client: &reqwest::Client,
-) -> Result<Vec<RestCountryResponse>, AppError> {
-    client
-        .get(COUNTRIES_API_URL)
-        .send()
-        .await
-        .map_err(|e| AppError::ApiError {
-            source: e,
-            api_name: "RestCountries".to_string(),
-        })?
-        .json::<Vec<RestCountryResponse>>()
-        .await
-        .map_err(|e| AppError::ApiError {
-            source: e,
-            api_name: "RestCountries (parsing)".to_string(),
+/// A provider of raw country data.
+#[async_trait]
+pub trait CountrySource: Send + Sync {
+    /// Human-readable name used in logs and error messages.
+    fn name(&self) -> &str;
+
+    async fn fetch_countries(&self) -> Result<Vec<CountryRecord>, AppError>;
+
+    /// The last good data this source saw, used when a live fetch fails.
+    fn cached_countries(&self) -> Option<Vec<CountryRecord>> {
+        None
+    }
+}
+
+/// A provider of USD-based exchange rates.
+#[async_trait]
+pub trait RateSource: Send + Sync {
+    /// Human-readable name used in logs and error messages.
+    fn name(&self) -> &str;
+
+    async fn fetch_rates(&self) -> Result<FetchedRates, AppError>;
+
+    /// The last good data this source saw, used when a live fetch fails.
+    fn cached_rates(&self) -> Option<FetchedRates> {
+        None
+    }
+}
+
+/// Controls how failed upstream requests are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one; `1` disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled for every further attempt.
+    pub base_delay: Duration,
+    /// Upper bound on the exponential delay (before jitter is added).
+    pub max_delay: Duration,
+    /// A random delay in `0..=jitter` added to every backoff.
+    pub jitter: Duration,
+    /// HTTP status codes that are worth retrying.
+    pub retryable_statuses: Vec<u16>,
+    /// Whether connection failures are retried.
+    pub retry_connect_errors: bool,
+    /// Whether request timeouts are retried.
+    pub retry_timeouts: bool,
+}
+
+impl RetryPolicy {
+    /// Returns true if the given error should be retried under this policy.
+    pub fn is_retryable(&self, err: &reqwest::Error) -> bool {
+        if let Some(status) = err.status() {
+            return self.retryable_statuses.contains(&status.as_u16());
+        }
+        (err.is_connect() && self.retry_connect_errors) || (err.is_timeout() && self.retry_timeouts)
+    }
+
+    /// Returns the backoff before the retry that follows `attempt` (1-based).
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let jitter_ms = self.jitter.as_millis() as u64;
+        let jitter = if jitter_ms > 0 {
+            Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_ms))
+        } else {
+            Duration::ZERO
+        };
+        backoff + jitter
+    }
+}
+
+/// Thresholds for the per-upstream circuit breakers.
+#[derive(Clone, Debug)]
+pub struct BreakerSettings {
+    /// Consecutive failed fetches (after retries) that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial request is let through.
+    pub cooldown: Duration,
+}
+
+/// Fails fast once an upstream has failed `failure_threshold` times in a row.
+///
+/// After the cool-down the breaker half-opens and lets a single trial request
+/// through; its outcome either closes the circuit again or re-opens it.
+pub struct CircuitBreaker {
+    name: String,
+    settings: BreakerSettings,
+    inner: Mutex<BreakerInner>,
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<DateTime<Utc>>,
+    /// When the next trial request may be let through.
+    retry_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, settings: BreakerSettings) -> Self {
+        CircuitBreaker {
+            name: name.to_string(),
+            settings,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                retry_at: None,
+            }),
+        }
+    }
+
+    /// Checks whether a request may be made, moving an expired open circuit to half-open.
+    pub fn acquire(&self) -> Result<(), AppError> {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        if inner.state == BreakerState::Closed {
+            return Ok(());
+        }
+
+        // While half-open the trial request holds the slot; if it never reports
+        // back (e.g. the caller was cancelled) another trial is allowed after a
+        // further cool-down.
+        let remaining = remaining(&inner);
+        if !remaining.is_zero() {
+            return Err(AppError::CircuitOpen {
+                api_name: self.name.clone(),
+                retry_after_secs: ceil_secs(remaining).max(1),
+            });
+        }
+
+        log::info!("{}: circuit half-open, allowing a trial request", self.name);
+        inner.state = BreakerState::HalfOpen;
+        inner.retry_at = Some(Instant::now() + self.settings.cooldown);
+        Ok(())
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        if inner.state != BreakerState::Closed {
+            log::info!("{}: circuit closed", self.name);
+        }
+        inner.state = BreakerState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.retry_at = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        inner.consecutive_failures += 1;
+        let should_open = inner.state == BreakerState::HalfOpen
+            || inner.consecutive_failures >= self.settings.failure_threshold;
+        if should_open {
+            log::warn!(
+                "{}: circuit open after {} consecutive failure(s)",
+                self.name,
+                inner.consecutive_failures
+            );
+            inner.state = BreakerState::Open;
+            inner.opened_at = Some(Utc::now());
+            inner.retry_at = Some(Instant::now() + self.settings.cooldown);
+        }
+    }
+
+    pub fn snapshot(&self) -> BreakerSnapshot {
+        let inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        let retry_after_secs = match inner.state {
+            BreakerState::Closed => None,
+            _ => Some(ceil_secs(remaining(&inner))),
+        };
+        BreakerSnapshot {
+            name: self.name.clone(),
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            opened_at: inner.opened_at,
+            retry_after_secs,
+        }
+    }
+}
+
+fn remaining(inner: &BreakerInner) -> Duration {
+    inner
+        .retry_at
+        .map(|at| at.saturating_duration_since(Instant::now()))
+        .unwrap_or(Duration::ZERO)
+}
+
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+/// The pair of sources a refresh pulls from.
+#[derive(Clone)]
+pub struct DataSources {
+    pub countries: Arc<dyn CountrySource>,
+    pub rates: Arc<dyn RateSource>,
+    /// Breakers guarding the upstreams in use, reported on `GET /status`.
+    pub breakers: Vec<Arc<CircuitBreaker>>,
+}
+
+impl DataSources {
+    /// Fetches countries, falling back to the last good snapshot if the source
+    /// fails. An open circuit is returned as is so clients get `Retry-After`.
+    pub async fn fetch_countries(&self) -> Result<FetchedCountries, AppError> {
+        match self.countries.fetch_countries().await {
+            Ok(countries) => Ok(FetchedCountries {
+                provider: self.countries.name().to_string(),
+                countries,
+            }),
+            Err(e @ AppError::CircuitOpen { .. }) => Err(e),
+            Err(e) => match self.countries.cached_countries() {
+                Some(countries) => {
+                    log::warn!(
+                        "{} failed ({}); using last good snapshot",
+                        self.countries.name(),
+                        e
+                    );
+                    Ok(FetchedCountries {
+                        provider: format!("{} (cached)", self.countries.name()),
+                        countries,
+                    })
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Fetches rates, falling back to the last good snapshot if the source
+    /// fails. An open circuit is returned as is so clients get `Retry-After`.
+    pub async fn fetch_rates(&self) -> Result<FetchedRates, AppError> {
+        match self.rates.fetch_rates().await {
+            Ok(rates) => Ok(rates),
+            Err(e @ AppError::CircuitOpen { .. }) => Err(e),
+            Err(e) => match self.rates.cached_rates() {
+                Some(rates) => {
+                    log::warn!(
+                        "{} failed ({}); using last good snapshot from {}",
+                        self.rates.name(),
+                        e,
+                        rates.provider
+                    );
+                    Ok(rates)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// Builds the country and rate sources selected by `DATA_SOURCE`.
+pub fn build_sources(config: &Config, client: reqwest::Client) -> Result<DataSources, AppError> {
+    let endpoint = |name: &str, url: &str| {
+        HttpEndpoint::new(
+            name,
+            client.clone(),
+            url,
+            config.retry.clone(),
+            Arc::new(CircuitBreaker::new(name, config.breaker.clone())),
+            UpstreamCache::new(&config.upstream_cache_dir, name),
+        )
+    };
+
+    let sources = match config.data_source {
+        DataSource::Http => {
+            let countries_url = match config.countries_schema {
+                CountriesSchema::V2 => COUNTRIES_API_URL,
+                CountriesSchema::V3_1 => COUNTRIES_V3_API_URL,
+            };
+            let countries_endpoint = endpoint("RestCountries", countries_url);
+            let mut breakers = vec![countries_endpoint.breaker.clone()];
+            let mut providers: Vec<Arc<dyn RateSource>> = Vec::new();
+            for provider in &config.rate_providers {
+                let source: Arc<dyn RateSource> = match provider {
+                    RateProvider::OpenErApi => {
+                        let endpoint = endpoint("OpenExchangeRates", EXCHANGE_RATE_API_URL);
+                        breakers.push(endpoint.breaker.clone());
+                        Arc::new(HttpRateSource::new(endpoint))
+                    }
+                    RateProvider::Ecb => {
+                        let endpoint = endpoint("ECB", ECB_RATES_URL);
+                        breakers.push(endpoint.breaker.clone());
+                        Arc::new(EcbRateSource::new(endpoint))
+                    }
+                    RateProvider::File => {
+                        Arc::new(FileRateSource::new(&config.rates_fixture_path))
+                    }
+                };
+                providers.push(source);
+            }
+
+            DataSources {
+                countries: Arc::new(HttpCountrySource::new(
+                    countries_endpoint,
+                    config.countries_schema,
+                )),
+                rates: Arc::new(FallbackRateSource::new(providers)),
+                breakers,
+            }
+        }
+        DataSource::File => DataSources {
+            countries: Arc::new(FileCountrySource::new(
+                &config.countries_fixture_path,
+                config.countries_schema,
+            )),
+            rates: Arc::new(FileRateSource::new(&config.rates_fixture_path)),
+            breakers: Vec::new(),
+        },
+        DataSource::Memory => DataSources {
+            countries: Arc::new(InMemoryCountrySource::new(decode_countries(
+                &read_file(Path::new(&config.countries_fixture_path))?,
+                config.countries_schema,
+                &config.countries_fixture_path,
+            )?)),
+            rates: Arc::new(InMemoryRateSource::new(read_json_file(Path::new(
+                &config.rates_fixture_path,
+            ))?)),
+            breakers: Vec::new(),
+        },
+    };
+    Ok(sources)
+}
+
+// --- HTTP sources ---
+
+/// An upstream URL with its retry policy, circuit breaker and on-disk cache.
+pub struct HttpEndpoint {
+    name: String,
+    client: reqwest::Client,
+    url: String,
+    retry: RetryPolicy,
+    breaker: Arc<CircuitBreaker>,
+    cache: UpstreamCache,
+}
+
+impl HttpEndpoint {
+    pub fn new(
+        name: &str,
+        client: reqwest::Client,
+        url: &str,
+        retry: RetryPolicy,
+        breaker: Arc<CircuitBreaker>,
+        cache: UpstreamCache,
+    ) -> Self {
+        HttpEndpoint {
+            name: name.to_string(),
+            client,
+            url: url.to_string(),
+            retry,
+            breaker,
+            cache,
+        }
+    }
+
+    /// Fetches and decodes the body, sending `If-None-Match`/`If-Modified-Since`
+    /// from the cached copy and reusing that copy when the upstream answers 304.
+    /// A fresh body is only cached once `decode` has accepted it. Also returns
+    /// when the upstream answered, which a 304 counts as.
+    pub async fn fetch<T>(
+        &self,
+        decode: impl Fn(&[u8]) -> Result<T, AppError>,
+    ) -> Result<(T, DateTime<Utc>), AppError> {
+        let cached = self.cache.load(&self.url);
+        let validators = cached.as_ref().map(|c| &c.validators);
+        let outcome = guarded(
+            &self.breaker,
+            fetch_body(&self.client, &self.url, &self.name, &self.retry, validators),
+        )
+        .await?;
+        let fetched_at = Utc::now();
+
+        match (outcome, cached) {
+            (FetchOutcome::Modified { body, validators }, _) => {
+                let decoded = decode(&body)?;
+                self.cache.store(&self.url, &validators, &body, fetched_at);
+                Ok((decoded, fetched_at))
+            }
+            (FetchOutcome::NotModified, Some(cached)) => {
+                log::info!("{}: not modified, reusing cached body", self.name);
+                Ok((decode(&cached.body)?, fetched_at))
+            }
+            (FetchOutcome::NotModified, None) => Err(AppError::Internal(anyhow::anyhow!(
+                "{} answered 304 but no cached body exists",
+                self.name
+            ))),
+        }
+    }
+
+    /// Decodes the last good body stored on disk, if any, together with when
+    /// it was fetched.
+    pub fn snapshot<T>(
+        &self,
+        decode: impl Fn(&[u8]) -> Result<T, AppError>,
+    ) -> Option<(T, DateTime<Utc>)> {
+        let cached = self.cache.load(&self.url)?;
+        log::info!(
+            "{}: loaded snapshot fetched at {}",
+            self.name,
+            cached.fetched_at
+        );
+        decode(&cached.body).ok().map(|decoded| (decoded, cached.fetched_at))
+    }
+}
+
+/// Fetches country data from the RestCountries API.
+pub struct HttpCountrySource {
+    endpoint: HttpEndpoint,
+    schema: CountriesSchema,
+}
+
+impl HttpCountrySource {
+    pub fn new(endpoint: HttpEndpoint, schema: CountriesSchema) -> Self {
+        HttpCountrySource { endpoint, schema }
+    }
+}
+
+#[async_trait]
+impl CountrySource for HttpCountrySource {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    async fn fetch_countries(&self) -> Result<Vec<CountryRecord>, AppError> {
+        let (countries, _) = self
+            .endpoint
+            .fetch(|body| decode_countries(body, self.schema, self.name()))
+            .await?;
+        Ok(countries)
+    }
+
+    fn cached_countries(&self) -> Option<Vec<CountryRecord>> {
+        let (countries, _) = self
+            .endpoint
+            .snapshot(|body| decode_countries(body, self.schema, self.name()))?;
+        Some(countries)
+    }
+}
+
+/// Fetches the latest USD exchange rates from open.er-api.com.
+pub struct HttpRateSource {
+    endpoint: HttpEndpoint,
+}
+
+impl HttpRateSource {
+    pub fn new(endpoint: HttpEndpoint) -> Self {
+        HttpRateSource { endpoint }
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<HashMap<String, f64>, AppError> {
+        let response: ExchangeRateResponse = decode_json(body, self.name())?;
+        Ok(response.rates)
+    }
+}
+
+#[async_trait]
+impl RateSource for HttpRateSource {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    async fn fetch_rates(&self) -> Result<FetchedRates, AppError> {
+        let (rates, fetched_at) = self.endpoint.fetch(|body| self.decode(body)).await?;
+        Ok(FetchedRates {
+            provider: self.name().to_string(),
+            rates,
+            fetched_at,
+        })
+    }
+
+    fn cached_rates(&self) -> Option<FetchedRates> {
+        let (rates, fetched_at) = self.endpoint.snapshot(|body| self.decode(body))?;
+        Some(FetchedRates {
+            provider: format!("{} (cached)", self.name()),
+            rates,
+            fetched_at,
         })
+    }
 }
 
-/// Fetches the latest USD exchange rates.
-pub async fn fetch_exchange_rates(
+/// Fetches the ECB euro reference rates and rebases them on USD.
+pub struct EcbRateSource {
+    endpoint: HttpEndpoint,
+}
+
+impl EcbRateSource {
+    pub fn new(endpoint: HttpEndpoint) -> Self {
+        EcbRateSource { endpoint }
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<HashMap<String, f64>, AppError> {
+        let eur_rates = parse_ecb_rates(body).map_err(|details| AppError::InvalidPayload {
+            api_name: self.name().to_string(),
+            details,
+        })?;
+        rebase_on_usd(eur_rates).ok_or_else(|| AppError::InvalidPayload {
+            api_name: self.name().to_string(),
+            details: "feed has no usable USD rate".to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl RateSource for EcbRateSource {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    async fn fetch_rates(&self) -> Result<FetchedRates, AppError> {
+        let (rates, fetched_at) = self.endpoint.fetch(|body| self.decode(body)).await?;
+        Ok(FetchedRates {
+            provider: self.name().to_string(),
+            rates,
+            fetched_at,
+        })
+    }
+
+    fn cached_rates(&self) -> Option<FetchedRates> {
+        let (rates, fetched_at) = self.endpoint.snapshot(|body| self.decode(body))?;
+        Some(FetchedRates {
+            provider: format!("{} (cached)", self.name()),
+            rates,
+            fetched_at,
+        })
+    }
+}
+
+/// Extracts `currency -> rate` pairs from the `<Cube currency=".." rate=".."/>`
+/// elements of an ECB reference-rate feed. Rates are quoted per 1 EUR.
+fn parse_ecb_rates(xml: &[u8]) -> Result<HashMap<String, f64>, String> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    let mut rates = HashMap::new();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"Cube" => {
+                let mut currency = None;
+                let mut rate = None;
+                for attr in e.attributes() {
+                    let attr = attr.map_err(|e| e.to_string())?;
+                    let value = attr.unescape_value().map_err(|e| e.to_string())?;
+                    match attr.key.as_ref() {
+                        b"currency" => currency = Some(value.to_string()),
+                        b"rate" => {
+                            rate = Some(value.parse::<f64>().map_err(|e| {
+                                format!("invalid rate '{}': {}", value, e)
+                            })?)
+                        }
+                        _ => {}
+                    }
+                }
+                if let (Some(currency), Some(rate)) = (currency, rate) {
+                    rates.insert(currency, rate);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    if rates.is_empty() {
+        return Err("feed contains no rates".to_string());
+    }
+    Ok(rates)
+}
+
+/// Converts EUR-quoted rates into USD-quoted ones (units of currency per 1 USD).
+fn rebase_on_usd(eur_rates: HashMap<String, f64>) -> Option<HashMap<String, f64>> {
+    let usd_per_eur = *eur_rates.get("USD").filter(|r| **r > 0.0)?;
+    let mut rates: HashMap<String, f64> = eur_rates
+        .into_iter()
+        .map(|(code, rate)| (code, rate / usd_per_eur))
+        .collect();
+    rates.insert("EUR".to_string(), 1.0 / usd_per_eur);
+    rates.insert("USD".to_string(), 1.0);
+    Some(rates)
+}
+
+/// Tries each rate provider in order and returns the first successful result.
+pub struct FallbackRateSource {
+    providers: Vec<Arc<dyn RateSource>>,
+}
+
+impl FallbackRateSource {
+    pub fn new(providers: Vec<Arc<dyn RateSource>>) -> Self {
+        FallbackRateSource { providers }
+    }
+}
+
+#[async_trait]
+impl RateSource for FallbackRateSource {
+    fn name(&self) -> &str {
+        "RateProviderChain"
+    }
+
+    async fn fetch_rates(&self) -> Result<FetchedRates, AppError> {
+        let mut last_err = None;
+        for provider in &self.providers {
+            match provider.fetch_rates().await {
+                Ok(rates) => return Ok(rates),
+                Err(e) => {
+                    log::warn!(
+                        "Rate provider {} failed: {}; trying next provider",
+                        provider.name(),
+                        e
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            AppError::Internal(anyhow::anyhow!("No exchange rate providers configured"))
+        }))
+    }
+
+    /// Only consulted once every provider has failed live, so a stale snapshot
+    /// never shadows a provider further down the chain that is still up.
+    fn cached_rates(&self) -> Option<FetchedRates> {
+        self.providers.iter().find_map(|provider| provider.cached_rates())
+    }
+}
+
+/// Runs `fetch` through `breaker`, failing fast while the circuit is open.
+async fn guarded<T>(
+    breaker: &CircuitBreaker,
+    fetch: impl std::future::Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    breaker.acquire()?;
+    let result = fetch.await;
+    match &result {
+        Ok(_) => breaker.record_success(),
+        Err(_) => breaker.record_failure(),
+    }
+    result
+}
+
+enum FetchOutcome {
+    Modified { body: Vec<u8>, validators: Validators },
+    NotModified,
+}
+
+/// GETs `url`, retrying according to `retry`. When `validators` are given the
+/// request is conditional and may come back as `NotModified`.
+async fn fetch_body(
     client: &reqwest::Client,
-) -> Result<ExchangeRateResponse, AppError> {
-    client
-        .get(EXCHANGE_RATE_API_URL)
-        .send()
-        .await
-        .map_err(|e| AppError::ApiError {
-            source: e,
-            api_name: "OpenExchangeRates".to_string(),
-        })?
-        .json::<ExchangeRateResponse>()
-        .await
-        .map_err(|e| AppError::ApiError {
-            source: e,
-            api_name: "OpenExchangeRates (parsing)".to_string(),
+    url: &str,
+    api_name: &str,
+    retry: &RetryPolicy,
+    validators: Option<&Validators>,
+) -> Result<FetchOutcome, AppError> {
+    let max_attempts = retry.max_attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        log::debug!("{}: attempt {}/{}", api_name, attempt, max_attempts);
+        let err = match try_fetch_body(client, url, validators).await {
+            Ok(outcome) => {
+                if attempt > 1 {
+                    log::info!("{}: succeeded on attempt {}/{}", api_name, attempt, max_attempts);
+                }
+                return Ok(outcome);
+            }
+            Err(e) => e,
+        };
+
+        if attempt < max_attempts && retry.is_retryable(&err) {
+            let delay = retry.delay_for(attempt);
+            log::warn!(
+                "{}: attempt {}/{} failed: {}; retrying in {}ms",
+                api_name,
+                attempt,
+                max_attempts,
+                err,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            continue;
+        }
+
+        log::error!(
+            "{}: attempt {}/{} failed: {}; giving up",
+            api_name,
+            attempt,
+            max_attempts,
+            err
+        );
+        return Err(AppError::ApiError {
+            source: err,
+            api_name: api_name.to_string(),
+            attempts: attempt,
+        });
+    }
+}
+
+async fn try_fetch_body(
+    client: &reqwest::Client,
+    url: &str,
+    validators: Option<&Validators>,
+) -> Result<FetchOutcome, reqwest::Error> {
+    let mut request = client.get(url);
+    if let Some(validators) = validators {
+        if let Some(etag) = &validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(FetchOutcome::NotModified);
+    }
+    let response = response.error_for_status()?;
+    let header_value = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header_value(header::ETAG),
+        last_modified: header_value(header::LAST_MODIFIED),
+    };
+    let body = response.bytes().await?;
+    Ok(FetchOutcome::Modified {
+        body: body.to_vec(),
+        validators,
+    })
+}
+
+fn decode_json<T: DeserializeOwned>(body: &[u8], api_name: &str) -> Result<T, AppError> {
+    serde_json::from_slice(body).map_err(|e| AppError::InvalidPayload {
+        api_name: api_name.to_string(),
+        details: e.to_string(),
+    })
+}
+
+/// Decodes a RestCountries payload in the given schema into the internal model.
+fn decode_countries(
+    body: &[u8],
+    schema: CountriesSchema,
+    api_name: &str,
+) -> Result<Vec<CountryRecord>, AppError> {
+    let countries = match schema {
+        CountriesSchema::V2 => decode_json::<Vec<RestCountryResponse>>(body, api_name)?
+            .into_iter()
+            .map(CountryRecord::from)
+            .collect(),
+        CountriesSchema::V3_1 => decode_json::<Vec<RestCountryV3Response>>(body, api_name)?
+            .into_iter()
+            .map(CountryRecord::from)
+            .collect(),
+    };
+    Ok(countries)
+}
+
+// --- Local JSON file sources ---
+
+/// Reads country data from a JSON file in the configured RestCountries shape.
+/// The file is re-read on every refresh.
+pub struct FileCountrySource {
+    path: PathBuf,
+    schema: CountriesSchema,
+}
+
+impl FileCountrySource {
+    pub fn new(path: impl Into<PathBuf>, schema: CountriesSchema) -> Self {
+        FileCountrySource {
+            path: path.into(),
+            schema,
+        }
+    }
+}
+
+#[async_trait]
+impl CountrySource for FileCountrySource {
+    fn name(&self) -> &str {
+        "CountriesFile"
+    }
+
+    async fn fetch_countries(&self) -> Result<Vec<CountryRecord>, AppError> {
+        let data = read_file(&self.path)?;
+        decode_countries(&data, self.schema, &self.path.display().to_string())
+    }
+}
+
+/// Reads exchange rates from a JSON file in the open.er-api.com shape.
+/// The file is re-read on every refresh.
+pub struct FileRateSource {
+    path: PathBuf,
+}
+
+impl FileRateSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileRateSource { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl RateSource for FileRateSource {
+    fn name(&self) -> &str {
+        "RatesFile"
+    }
+
+    async fn fetch_rates(&self) -> Result<FetchedRates, AppError> {
+        let response: ExchangeRateResponse = read_json_file(&self.path)?;
+        Ok(FetchedRates {
+            provider: self.name().to_string(),
+            rates: response.rates,
+            fetched_at: Utc::now(),
         })
-}
\ No newline at end of file
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, AppError> {
+    fs::read(path).map_err(|e| {
+        AppError::Internal(anyhow::anyhow!(
+            "Failed to read data file '{}': {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+fn read_json_file<T: DeserializeOwned>(path: &Path) -> Result<T, AppError> {
+    let data = read_file(path)?;
+    serde_json::from_slice(&data).map_err(|e| {
+        AppError::Internal(anyhow::anyhow!(
+            "Failed to parse data file '{}': {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+// --- In-memory sources ---
+
+/// Serves a fixed set of countries held in memory.
+pub struct InMemoryCountrySource {
+    countries: Vec<CountryRecord>,
+}
+
+impl InMemoryCountrySource {
+    pub fn new(countries: Vec<CountryRecord>) -> Self {
+        InMemoryCountrySource { countries }
+    }
+}
+
+#[async_trait]
+impl CountrySource for InMemoryCountrySource {
+    fn name(&self) -> &str {
+        "InMemoryCountries"
+    }
+
+    async fn fetch_countries(&self) -> Result<Vec<CountryRecord>, AppError> {
+        Ok(self.countries.clone())
+    }
+}
+
+/// Serves a fixed set of exchange rates held in memory.
+pub struct InMemoryRateSource {
+    rates: ExchangeRateResponse,
+}
+
+impl InMemoryRateSource {
+    pub fn new(rates: ExchangeRateResponse) -> Self {
+        InMemoryRateSource { rates }
+    }
+}
+
+#[async_trait]
+impl RateSource for InMemoryRateSource {
+    fn name(&self) -> &str {
+        "InMemoryRates"
+    }
+
+    async fn fetch_rates(&self) -> Result<FetchedRates, AppError> {
+        Ok(FetchedRates {
+            provider: self.name().to_string(),
+            rates: self.rates.rates.clone(),
+            fetched_at: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "Test",
+            BreakerSettings {
+                failure_threshold,
+                cooldown,
+            },
+        )
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold() {
+        let breaker = breaker(3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.snapshot().state, BreakerState::Closed);
+        assert!(breaker.acquire().is_ok());
+
+        breaker.record_failure();
+        let snapshot = breaker.snapshot();
+        assert_eq!(snapshot.state, BreakerState::Open);
+        assert!(snapshot.opened_at.is_some());
+        match breaker.acquire() {
+            Err(AppError::CircuitOpen {
+                api_name,
+                retry_after_secs,
+            }) => {
+                assert_eq!(api_name, "Test");
+                assert!((1..=60).contains(&retry_after_secs));
+            }
+            other => panic!("expected CircuitOpen, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        let snapshot = breaker.snapshot();
+        assert_eq!(snapshot.state, BreakerState::Closed);
+        assert_eq!(snapshot.consecutive_failures, 1);
+    }
+
+    #[test]
+    fn half_open_trial_success_closes() {
+        let breaker = breaker(1, Duration::ZERO);
+        breaker.record_failure();
+        assert_eq!(breaker.snapshot().state, BreakerState::Open);
+
+        assert!(breaker.acquire().is_ok());
+        assert_eq!(breaker.snapshot().state, BreakerState::HalfOpen);
+        breaker.record_success();
+        let snapshot = breaker.snapshot();
+        assert_eq!(snapshot.state, BreakerState::Closed);
+        assert_eq!(snapshot.consecutive_failures, 0);
+        assert!(snapshot.opened_at.is_none());
+        assert!(snapshot.retry_after_secs.is_none());
+    }
+
+    #[test]
+    fn half_open_trial_failure_reopens() {
+        let breaker = breaker(3, Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert!(breaker.acquire().is_ok());
+        assert_eq!(breaker.snapshot().state, BreakerState::HalfOpen);
+
+        // A single failed trial is enough, whatever the threshold
+        breaker.record_failure();
+        assert_eq!(breaker.snapshot().state, BreakerState::Open);
+    }
+
+    const ECB_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gesmes:Envelope xmlns:gesmes="http://www.gesmes.org/xml/2002-08-01" xmlns="http://www.ecb.int/vocabulary/2002-08-01/eurofxref">
+  <gesmes:subject>Reference rates</gesmes:subject>
+  <Cube>
+    <Cube time="2025-10-24">
+      <Cube currency="USD" rate="1.25"/>
+      <Cube currency="GBP" rate="0.875"/>
+    </Cube>
+  </Cube>
+</gesmes:Envelope>"#;
+
+    #[test]
+    fn parses_ecb_feed() {
+        let rates = parse_ecb_rates(ECB_FEED.as_bytes()).unwrap();
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates["USD"], 1.25);
+        assert_eq!(rates["GBP"], 0.875);
+    }
+
+    #[test]
+    fn rejects_bad_ecb_feeds() {
+        let empty = parse_ecb_rates(b"<Cube><Cube time=\"2025-10-24\"/></Cube>").unwrap_err();
+        assert_eq!(empty, "feed contains no rates");
+        let bad_rate = parse_ecb_rates(b"<Cube currency=\"USD\" rate=\"abc\"/>").unwrap_err();
+        assert!(bad_rate.starts_with("invalid rate 'abc'"), "{}", bad_rate);
+    }
+
+    #[test]
+    fn rebases_ecb_rates_on_usd() {
+        let rates = rebase_on_usd(parse_ecb_rates(ECB_FEED.as_bytes()).unwrap()).unwrap();
+        assert_eq!(rates["USD"], 1.0);
+        assert_eq!(rates["EUR"], 0.8);
+        assert_eq!(rates["GBP"], 0.7);
+    }
+
+    #[test]
+    fn rebase_needs_a_usd_rate() {
+        let without_usd = HashMap::from([("GBP".to_string(), 0.875)]);
+        assert!(rebase_on_usd(without_usd).is_none());
+        let zero_usd = HashMap::from([("USD".to_string(), 0.0)]);
+        assert!(rebase_on_usd(zero_usd).is_none());
+    }
+}
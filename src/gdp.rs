@@ -0,0 +1,172 @@
+use crate::config::GdpStrategy;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::sync::Arc;
+
+const MIN_MULTIPLIER: f64 = 1000.0;
+const MAX_MULTIPLIER: f64 = 2000.0;
+
+/// Computes `estimated_gdp = population * multiplier / exchange_rate`.
+///
+/// Strategies differ only in how the multiplier is picked.
+pub trait GdpEstimator: Send + Sync {
+    /// Strategy name recorded in `app_status`.
+    fn name(&self) -> &'static str;
+
+    /// Seed recorded in `app_status`, for strategies that use one.
+    fn seed(&self) -> Option<u64> {
+        None
+    }
+
+    fn multiplier(&self, country_name: &str) -> f64;
+
+    fn estimate(&self, country_name: &str, population: i64, rate: f64) -> f64 {
+        (population as f64 * self.multiplier(country_name)) / rate
+    }
+}
+
+/// A fresh random multiplier in 1000–2000 on every call (the original behaviour).
+pub struct RandomEstimator;
+
+impl GdpEstimator for RandomEstimator {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn multiplier(&self, _country_name: &str) -> f64 {
+        rand::thread_rng().gen_range(MIN_MULTIPLIER..=MAX_MULTIPLIER)
+    }
+}
+
+/// A random multiplier in 1000–2000 drawn from an RNG seeded with the
+/// configured seed and the country name, so the same inputs always give the
+/// same estimate regardless of processing order.
+///
+/// ChaCha8 is used instead of `StdRng`, whose algorithm may change between
+/// `rand` releases, and its output is mapped onto the range by hand for the
+/// same reason.
+pub struct SeededEstimator {
+    seed: u64,
+}
+
+impl SeededEstimator {
+    pub fn new(seed: u64) -> Self {
+        SeededEstimator { seed }
+    }
+}
+
+impl GdpEstimator for SeededEstimator {
+    fn name(&self) -> &'static str {
+        "seeded"
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(self.seed)
+    }
+
+    fn multiplier(&self, country_name: &str) -> f64 {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed ^ fnv1a(country_name.as_bytes()));
+        // The top 53 bits as a fraction in [0, 1]
+        let fraction = (rng.next_u64() >> 11) as f64 / ((1u64 << 53) - 1) as f64;
+        MIN_MULTIPLIER + fraction * (MAX_MULTIPLIER - MIN_MULTIPLIER)
+    }
+}
+
+/// The same multiplier for every country.
+pub struct FixedEstimator {
+    multiplier: f64,
+}
+
+impl FixedEstimator {
+    pub fn new(multiplier: f64) -> Self {
+        FixedEstimator { multiplier }
+    }
+}
+
+impl GdpEstimator for FixedEstimator {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn multiplier(&self, _country_name: &str) -> f64 {
+        self.multiplier
+    }
+}
+
+/// Builds the estimator selected by `GDP_STRATEGY`.
+pub fn build_estimator(strategy: &GdpStrategy) -> Arc<dyn GdpEstimator> {
+    match *strategy {
+        GdpStrategy::Random => Arc::new(RandomEstimator),
+        GdpStrategy::Seeded { seed } => Arc::new(SeededEstimator::new(seed)),
+        GdpStrategy::Fixed { multiplier } => Arc::new(FixedEstimator::new(multiplier)),
+    }
+}
+
+/// 64-bit FNV-1a. Used instead of `DefaultHasher`, whose output may change
+/// between Rust releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_multiplier_is_stable_across_instances() {
+        let first = SeededEstimator::new(42);
+        let second = SeededEstimator::new(42);
+        for name in ["Nigeria", "Ghana", "Côte d'Ivoire"] {
+            assert_eq!(first.multiplier(name), second.multiplier(name));
+        }
+    }
+
+    #[test]
+    fn seeded_multiplier_is_pinned() {
+        // If this fails, seeded estimates no longer match earlier refreshes
+        assert_eq!(
+            SeededEstimator::new(42).multiplier("Nigeria"),
+            1458.996764915702
+        );
+    }
+
+    #[test]
+    fn seeded_multiplier_depends_on_seed_and_name() {
+        let estimator = SeededEstimator::new(42);
+        assert_ne!(
+            estimator.multiplier("Nigeria"),
+            estimator.multiplier("Ghana")
+        );
+        assert_ne!(
+            estimator.multiplier("Nigeria"),
+            SeededEstimator::new(43).multiplier("Nigeria")
+        );
+    }
+
+    #[test]
+    fn multipliers_stay_in_range() {
+        let seeded = SeededEstimator::new(7);
+        for i in 0..1000 {
+            let name = format!("Country {}", i);
+            for multiplier in [seeded.multiplier(&name), RandomEstimator.multiplier(&name)] {
+                assert!(
+                    (MIN_MULTIPLIER..=MAX_MULTIPLIER).contains(&multiplier),
+                    "{} out of range for {}",
+                    multiplier,
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_estimate() {
+        let estimator = FixedEstimator::new(1500.0);
+        assert_eq!(estimator.estimate("Nigeria", 1_000, 1.5), 1_000_000.0);
+        assert_eq!(estimator.estimate("Ghana", 0, 10.0), 0.0);
+    }
+}
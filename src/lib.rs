@@ -0,0 +1,16 @@
+//! Country and currency data service: upstream fetching, persistence and the
+//! HTTP API. The binary in `main.rs` wires these together; benchmarks use them
+//! directly.
+
+pub mod cache;
+pub mod config;
+pub mod db;
+pub mod error;
+pub mod external;
+pub mod gdp;
+pub mod image;
+pub mod models;
+pub mod names;
+pub mod pagination;
+pub mod routes;
+pub mod validation;
@@ -2,15 +2,8 @@ use actix_web::{web, App, HttpServer};
 use sqlx::mysql::MySqlPoolOptions;
 use std::io::Result as IoResult;
 
-// Declare all the modules
-mod config;
-mod db;
-mod error;
-mod external;
-mod image;
-mod models;
-mod routes;
-
+// The modules live in the library crate (src/lib.rs)
+use hng13_stage2_country_currency_service::{config, external, gdp, routes};
 use routes::AppState;
 
 #[tokio::main]
@@ -50,10 +43,28 @@ async fn main() -> IoResult<()> {
         .expect("Failed to run database migrations");
     log::info!("Database migrations complete.");
 
+    // Setup data sources
+    let http_client = reqwest::Client::builder()
+        .timeout(config.http_timeout)
+        .build()
+        .expect("Failed to build HTTP client");
+    let sources = external::build_sources(&config, http_client)
+        .expect("Failed to initialise data sources");
+    log::info!(
+        "Using data sources: {} / {}",
+        sources.countries.name(),
+        sources.rates.name()
+    );
+
+    let gdp_estimator = gdp::build_estimator(&config.gdp_strategy);
+    log::info!("Using '{}' GDP estimation strategy", gdp_estimator.name());
+
     // Setup shared application state
     let app_state = web::Data::new(AppState {
         db_pool,
-        http_client: reqwest::Client::new(),
+        sources,
+        gdp_estimator,
+        refresh_settings: config.refresh.clone(),
     });
 
     let server_address = format!("0.0.0.0:{}", config.port);
@@ -1,16 +1,23 @@
 use chrono::{DateTime, Utc};
+use serde::de::{Deserializer, MapAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use std::collections::HashMap;
+use std::fmt;
 
 // --- External API Response Models ---
 
+/// RestCountries v2 (`/v2/all?fields=...`) currency entry.
 #[derive(Debug, Deserialize)]
 pub struct RestCountryCurrency {
     pub code: String,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
 }
 
+/// RestCountries v2 country.
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct RestCountryResponse {
     pub name: String,
     pub capital: Option<String>,
@@ -18,42 +25,456 @@ pub struct RestCountryResponse {
     pub population: i64,
     pub flag: Option<String>,
     pub currencies: Option<Vec<RestCountryCurrency>>,
+    #[serde(default)]
+    pub alt_spellings: Vec<String>,
+    pub alpha2_code: Option<String>,
+    pub alpha3_code: Option<String>,
+    pub numeric_code: Option<String>,
 }
 
+/// RestCountries v3.1 country name block.
 #[derive(Debug, Deserialize)]
+pub struct RestCountryV3Name {
+    pub common: String,
+    pub official: Option<String>,
+}
+
+/// RestCountries v3.1 currency entry (keyed by ISO code in the parent map).
+#[derive(Debug, Deserialize)]
+pub struct RestCountryV3Currency {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+}
+
+/// RestCountries v3.1 flag URLs.
+#[derive(Debug, Deserialize)]
+pub struct RestCountryV3Flags {
+    pub png: Option<String>,
+    pub svg: Option<String>,
+}
+
+/// RestCountries v3.1 country.
+#[derive(Debug, Deserialize)]
+pub struct RestCountryV3Response {
+    pub name: RestCountryV3Name,
+    #[serde(default)]
+    pub capital: Vec<String>,
+    pub region: Option<String>,
+    pub population: i64,
+    pub flags: Option<RestCountryV3Flags>,
+    /// Kept in document order so the first listed currency stays the primary one.
+    #[serde(default, deserialize_with = "ordered_map")]
+    pub currencies: Vec<(String, RestCountryV3Currency)>,
+    #[serde(default, rename = "altSpellings")]
+    pub alt_spellings: Vec<String>,
+    pub cca2: Option<String>,
+    pub cca3: Option<String>,
+    pub ccn3: Option<String>,
+}
+
+/// Deserializes a JSON object into `(key, value)` pairs, preserving key order.
+fn ordered_map<'de, D, V>(deserializer: D) -> Result<Vec<(String, V)>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    struct OrderedMapVisitor<V>(std::marker::PhantomData<V>);
+
+    impl<'de, V: Deserialize<'de>> Visitor<'de> for OrderedMapVisitor<V> {
+        type Value = Vec<(String, V)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map")
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E> {
+            Ok(Vec::new())
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(entries)
+        }
+    }
+
+    deserializer.deserialize_any(OrderedMapVisitor(std::marker::PhantomData))
+}
+
+/// A currency as reported by the country source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrencyInfo {
+    pub code: String,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+}
+
+/// Source-independent country data fed to `db::refresh_data`.
+#[derive(Debug, Clone)]
+pub struct CountryRecord {
+    pub name: String,
+    pub capital: Option<String>,
+    pub region: Option<String>,
+    pub population: i64,
+    pub flag_url: Option<String>,
+    /// In source order; the first entry is the primary currency.
+    pub currencies: Vec<CurrencyInfo>,
+    /// Other names the country is known by; stored as lookup aliases.
+    pub alt_spellings: Vec<String>,
+    /// ISO 3166-1 codes.
+    pub alpha2_code: Option<String>,
+    pub alpha3_code: Option<String>,
+    pub numeric_code: Option<String>,
+}
+
+impl From<RestCountryResponse> for CountryRecord {
+    fn from(c: RestCountryResponse) -> Self {
+        CountryRecord {
+            name: c.name,
+            capital: c.capital,
+            region: c.region,
+            population: c.population,
+            flag_url: c.flag,
+            currencies: c
+                .currencies
+                .unwrap_or_default()
+                .into_iter()
+                .map(|cur| CurrencyInfo {
+                    code: cur.code,
+                    name: cur.name,
+                    symbol: cur.symbol,
+                })
+                .collect(),
+            alt_spellings: c.alt_spellings,
+            alpha2_code: c.alpha2_code,
+            alpha3_code: c.alpha3_code,
+            numeric_code: c.numeric_code,
+        }
+    }
+}
+
+impl From<RestCountryV3Response> for CountryRecord {
+    fn from(c: RestCountryV3Response) -> Self {
+        CountryRecord {
+            name: c.name.common,
+            capital: c.capital.into_iter().next(),
+            region: c.region,
+            population: c.population,
+            flag_url: c.flags.and_then(|f| f.svg.or(f.png)),
+            currencies: c
+                .currencies
+                .into_iter()
+                .map(|(code, cur)| CurrencyInfo {
+                    code,
+                    name: cur.name,
+                    symbol: cur.symbol,
+                })
+                .collect(),
+            // The official name is as good a lookup key as any alternative spelling
+            alt_spellings: c.name.official.into_iter().chain(c.alt_spellings).collect(),
+            alpha2_code: c.cca2,
+            alpha3_code: c.cca3,
+            numeric_code: c.ccn3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct ExchangeRateResponse {
     pub rates: HashMap<String, f64>,
 }
 
+/// Countries together with the provider that served them.
+#[derive(Debug, Clone)]
+pub struct FetchedCountries {
+    pub provider: String,
+    pub countries: Vec<CountryRecord>,
+}
+
+/// USD-based rates together with the provider that served them.
+#[derive(Debug, Clone)]
+pub struct FetchedRates {
+    pub provider: String,
+    pub rates: HashMap<String, f64>,
+    /// When the provider served these rates; for a snapshot, when it was taken.
+    pub fetched_at: DateTime<Utc>,
+}
+
 // --- Database & Internal Models ---
 
 #[derive(Debug, Serialize, FromRow, Clone)]
 pub struct Country {
     pub id: i32,
     pub name: String,
+    /// ISO 3166-1 codes; `/countries/{id}` accepts the alpha ones in place of the name.
+    pub alpha2_code: Option<String>,
+    pub alpha3_code: Option<String>,
+    pub numeric_code: Option<String>,
     pub capital: Option<String>,
     pub region: Option<String>,
     pub population: i64,
+    /// The first listed currency; `exchange_rate` and `estimated_gdp` are based on it.
     pub currency_code: Option<String>,
+    /// Same as `currency_code`, named to go with `currencies`.
+    #[sqlx(skip)]
+    pub primary_currency: Option<String>,
+    /// All currencies, primary first. Loaded separately from `country_currencies`.
+    #[sqlx(skip)]
+    pub currencies: Vec<CountryCurrency>,
     pub exchange_rate: Option<sqlx::types::Decimal>,
     pub estimated_gdp: Option<sqlx::types::Decimal>,
     pub flag_url: Option<String>,
     pub last_refreshed_at: DateTime<Utc>,
+    /// Set once the country stopped appearing upstream (`STALE_COUNTRY_POLICY=mark`).
+    pub stale_since: Option<DateTime<Utc>>,
+    /// Written through the API; refreshes leave it alone unless forced.
+    pub manual_override: bool,
+    /// Set by `DELETE /countries/{id}`; only listed with `include_deleted=true`.
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CountryCurrency {
+    pub code: String,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct AppStatus {
     pub total_countries: i32,
     pub last_refreshed_at: Option<DateTime<Utc>>,
+    pub rate_provider: Option<String>,
+    pub gdp_strategy: Option<String>,
+    pub gdp_seed: Option<u64>,
+}
+
+/// Counts of how a refresh changed the `countries` table.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RefreshStats {
+    pub inserted: i32,
+    pub updated: i32,
+    pub unchanged: i32,
+    pub removed: i32,
+    /// Manual overrides left as they were.
+    pub skipped: i32,
+}
+
+/// Result of `db::refresh_data`.
+#[derive(Debug)]
+pub struct RefreshOutcome {
+    pub status: AppStatus,
+    pub top_countries: Vec<Country>,
+    pub stats: RefreshStats,
+    /// Stored countries missing from this refresh's upstream data.
+    pub stale_countries: Vec<String>,
+}
+
+/// What a refresh run did, written to `refresh_runs` when it finishes.
+#[derive(Debug, Default)]
+pub struct RefreshRunSummary {
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_ms: i64,
+    pub stats: RefreshStats,
+    pub country_provider: Option<String>,
+    pub rate_provider: Option<String>,
+    /// Set when the run failed.
+    pub failure_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct RefreshRun {
+    pub id: i32,
+    pub status: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<i64>,
+    pub countries_inserted: i32,
+    pub countries_updated: i32,
+    pub countries_unchanged: i32,
+    pub countries_removed: i32,
+    pub countries_skipped: i32,
+    pub country_provider: Option<String>,
+    pub rate_provider: Option<String>,
+    pub failure_reason: Option<String>,
+}
+
+/// A currency and the countries using it, built by `db::get_currencies`.
+#[derive(Debug, Serialize, FromRow)]
+pub struct CurrencySummary {
+    pub code: String,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    /// The latest stored USD rate; `None` if no refresh has fetched one.
+    #[sqlx(skip)]
+    pub usd_rate: Option<sqlx::types::Decimal>,
+    #[sqlx(skip)]
+    pub rate_fetched_at: Option<DateTime<Utc>>,
+    /// Countries listing the currency, primary or not.
+    pub country_count: i64,
+    pub total_population: i64,
+    /// Sum of the countries' `estimated_gdp`; countries without one are left out.
+    pub total_estimated_gdp: Option<sqlx::types::Decimal>,
+    #[sqlx(skip)]
+    pub countries: Vec<String>,
+}
+
+/// Aggregates over the countries of one region, built by `db::get_regions`.
+#[derive(Debug, Serialize, FromRow)]
+pub struct RegionSummary {
+    pub region: String,
+    pub country_count: i64,
+    pub total_population: i64,
+    #[sqlx(skip)]
+    pub median_population: f64,
+    /// Over the countries that have an estimate.
+    pub total_estimated_gdp: Option<sqlx::types::Decimal>,
+    pub mean_estimated_gdp: Option<sqlx::types::Decimal>,
+    /// Distinct codes across all of the countries' currencies.
+    #[sqlx(skip)]
+    pub currency_count: i64,
+    /// Countries without an `exchange_rate`, including those without a currency.
+    pub missing_rate_count: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct RateHistoryPoint {
+    pub rate: sqlx::types::Decimal,
+    pub fetched_at: DateTime<Utc>,
+    pub refresh_run: Option<i32>,
+    pub provider: Option<String>,
 }
 
 // --- API Query Parameters ---
 
+/// Raw `GET /countries` parameters. Numbers are kept as strings so
+/// `validation` can report malformed values itself.
 #[derive(Debug, Deserialize)]
 pub struct GetCountriesQuery {
     pub region: Option<String>,
     pub currency: Option<String>,
     pub sort: Option<String>,
+    /// Any of `limit`, `offset` or `cursor` switches the response to a `CountryPage`.
+    pub limit: Option<String>,
+    pub offset: Option<String>,
+    pub cursor: Option<String>,
+    /// Inclusive bounds.
+    pub min_population: Option<String>,
+    pub max_population: Option<String>,
+    pub min_gdp: Option<String>,
+    pub max_gdp: Option<String>,
+    pub min_rate: Option<String>,
+    pub max_rate: Option<String>,
+    /// `true` also lists soft-deleted countries.
+    pub include_deleted: Option<String>,
+}
+
+/// The filters of a `GetCountriesQuery`, parsed and checked by `validation`.
+#[derive(Debug)]
+pub struct CountryFilters {
+    pub region: Option<String>,
+    pub currency: Option<String>,
+    pub min_population: Option<i64>,
+    pub max_population: Option<i64>,
+    pub min_gdp: Option<sqlx::types::Decimal>,
+    pub max_gdp: Option<sqlx::types::Decimal>,
+    pub min_rate: Option<sqlx::types::Decimal>,
+    pub max_rate: Option<sqlx::types::Decimal>,
+    pub include_deleted: bool,
+}
+
+/// Body of `POST /countries`, `PUT /countries/{id}` and `PATCH /countries/{id}`.
+///
+/// Every field is optional here so that `validation::country_payload` can
+/// report all missing ones at once. For the nullable fields, the outer
+/// `Option` tells an absent field (`PATCH` keeps it) from an explicit `null`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CountryPayload {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub capital: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub region: Option<Option<String>>,
+    pub population: Option<i64>,
+    #[serde(default, deserialize_with = "present")]
+    pub flag_url: Option<Option<String>>,
+    /// Primary currency first.
+    pub currencies: Option<Vec<CurrencyPayload>>,
+    pub alt_spellings: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub alpha2_code: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub alpha3_code: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub numeric_code: Option<Option<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CurrencyPayload {
+    pub code: String,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+}
+
+/// Deserializes a field that is present in the input, `null` or not, as `Some`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// One `ORDER BY` term of `GET /countries`; `column` is from `db::SORT_FIELDS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SortKey {
+    pub column: &'static str,
+    pub descending: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompareCountriesQuery {
+    /// Comma-separated names or ISO alpha codes.
+    pub names: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchCountriesQuery {
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshQuery {
+    /// Also overwrite countries marked as manual overrides.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Raw `GET /convert` parameters, checked by `validation::conversion`.
+#[derive(Debug, Deserialize)]
+pub struct ConvertQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub amount: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateHistoryQuery {
+    /// Inclusive lower bound (RFC 3339).
+    pub from: Option<DateTime<Utc>>,
+    /// Inclusive upper bound (RFC 3339).
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListRefreshRunsQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
 }
 
 // --- API Response Models ---
@@ -61,6 +482,135 @@ pub struct GetCountriesQuery {
 #[derive(Debug, Serialize)]
 pub struct RefreshResponse {
     pub status: String,
+    pub run_id: i32,
     pub countries_processed: usize,
     pub last_refreshed_at: DateTime<Utc>,
-}
\ No newline at end of file
+    pub country_provider: String,
+    pub rate_provider: String,
+    pub changes: RefreshStats,
+    /// `delete`, `mark` or `keep`: what was done with `stale_countries`.
+    pub stale_policy: String,
+    pub stale_countries: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusResponse {
+    #[serde(flatten)]
+    pub status: AppStatus,
+    pub upstreams: Vec<BreakerSnapshot>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BreakerSnapshot {
+    pub name: String,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub opened_at: Option<DateTime<Utc>>,
+    pub retry_after_secs: Option<u64>,
+}
+
+/// One country of a `GET /countries/compare` response.
+#[derive(Debug, Serialize)]
+pub struct CountryComparison {
+    pub country: Country,
+    pub metrics: ComparisonMetrics,
+}
+
+/// Figures derived from a country and the rest of the table. Ranks count
+/// from 1 over all countries that aren't deleted; ties share a rank.
+#[derive(Debug, Serialize)]
+pub struct ComparisonMetrics {
+    pub gdp_per_capita: Option<sqlx::types::Decimal>,
+    /// The country's fraction of its region's population.
+    pub region_population_share: Option<sqlx::types::Decimal>,
+    /// `None` without a GDP estimate.
+    pub gdp_rank: Option<i64>,
+    pub population_rank: i64,
+}
+
+/// A page of `GET /countries` results.
+#[derive(Debug, Serialize)]
+pub struct CountryPage {
+    pub items: Vec<Country>,
+    /// Countries matching the filters across all pages.
+    pub total: i64,
+    /// Pass as `cursor` to fetch the next page; `null` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversionResponse {
+    pub from: String,
+    pub to: String,
+    pub amount: sqlx::types::Decimal,
+    pub converted: sqlx::types::Decimal,
+    /// Units of `to` per unit of `from`, crossed through USD.
+    pub rate: sqlx::types::Decimal,
+    /// When the older of the two USD rates was fetched.
+    pub rate_fetched_at: DateTime<Utc>,
+    /// The cached USD rates the conversion is based on.
+    pub from_usd_rate: RateHistoryPoint,
+    pub to_usd_rate: RateHistoryPoint,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RateHistoryResponse {
+    pub currency_code: String,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub points: Vec<RateHistoryPoint>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn currency_codes(json: &str) -> Vec<String> {
+        let country: RestCountryV3Response = serde_json::from_str(json).unwrap();
+        country
+            .currencies
+            .into_iter()
+            .map(|(code, _)| code)
+            .collect()
+    }
+
+    #[test]
+    fn currencies_keep_document_order() {
+        let codes = currency_codes(
+            r#"{
+                "name": { "common": "Zimbabwe" },
+                "population": 15000000,
+                "currencies": {
+                    "ZWL": { "name": "Zimbabwean dollar", "symbol": "$" },
+                    "BWP": { "name": "Botswana pula", "symbol": "P" },
+                    "AUD": { "name": "Australian dollar", "symbol": "$" }
+                }
+            }"#,
+        );
+        assert_eq!(codes, ["ZWL", "BWP", "AUD"]);
+    }
+
+    #[test]
+    fn missing_or_null_currencies_are_empty() {
+        let missing = r#"{ "name": { "common": "Antarctica" }, "population": 1000 }"#;
+        assert!(currency_codes(missing).is_empty());
+        let null =
+            r#"{ "name": { "common": "Antarctica" }, "population": 1000, "currencies": null }"#;
+        assert!(currency_codes(null).is_empty());
+    }
+
+    #[test]
+    fn currencies_must_be_an_object() {
+        let json = r#"{ "name": { "common": "Nowhere" }, "population": 1, "currencies": ["USD"] }"#;
+        let err = serde_json::from_str::<RestCountryV3Response>(json).unwrap_err();
+        assert!(err.to_string().contains("expected a map"), "{}", err);
+    }
+}
@@ -0,0 +1,163 @@
+use strsim::osa_distance;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Most names suggested after a failed lookup.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Folds a name for lookups: accents stripped, lowercased, and every run of
+/// punctuation or whitespace collapsed to one space, so "Côte d’Ivoire" and
+/// "cote d'ivoire" both become "cote d ivoire".
+pub fn normalize(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    let mut pending_space = false;
+    for c in name.nfkd().filter(|c| !is_combining_mark(*c)) {
+        if c.is_alphanumeric() {
+            if pending_space && !normalized.is_empty() {
+                normalized.push(' ');
+            }
+            pending_space = false;
+            normalized.extend(c.to_lowercase());
+        } else {
+            pending_space = true;
+        }
+    }
+    normalized
+}
+
+/// The key `countries.name` is compared by: the column's collation ignores
+/// case and accents but not punctuation, so "Curaçao" and "curacao" share a
+/// key while "Guinea-Bissau" and "Guinea Bissau" do not.
+pub fn collation_key(name: &str) -> String {
+    name.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The alternative names stored for a country: `alt_spellings` without
+/// blanks and without entries that fold to the name itself or to each other.
+pub fn aliases(name: &str, alt_spellings: &[String]) -> Vec<String> {
+    let mut seen = vec![normalize(name)];
+    let mut aliases = Vec::new();
+    for alias in alt_spellings {
+        let normalized = normalize(alias);
+        if normalized.is_empty() || seen.contains(&normalized) {
+            continue;
+        }
+        seen.push(normalized);
+        aliases.push(alias.trim().to_string());
+    }
+    aliases
+}
+
+/// Picks the names closest to `query` among `candidates`, given as
+/// `(name, normalized form)` pairs, one per name or alias.
+///
+/// A candidate qualifies if it starts with the query or is within an edit
+/// distance of a third of the query's length (at least 2). Swapping two
+/// adjacent letters counts as one edit.
+pub fn suggest(query: &str, candidates: &[(String, String)]) -> Vec<String> {
+    let query = normalize(query);
+    if query.is_empty() {
+        return Vec::new();
+    }
+    let max_distance = (query.chars().count() / 3).max(2);
+
+    let mut scored: Vec<(usize, &str)> = candidates
+        .iter()
+        .filter_map(|(name, normalized)| {
+            let distance = osa_distance(&query, normalized);
+            (distance <= max_distance || normalized.starts_with(&query))
+                .then_some((distance, name.as_str()))
+        })
+        .collect();
+    scored.sort();
+
+    let mut suggestions: Vec<String> = Vec::new();
+    for (_, name) in scored {
+        if !suggestions.iter().any(|s| s == name) {
+            suggestions.push(name.to_string());
+        }
+        if suggestions.len() == MAX_SUGGESTIONS {
+            break;
+        }
+    }
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(names: &[&str]) -> Vec<(String, String)> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), normalize(name)))
+            .collect()
+    }
+
+    #[test]
+    fn normalize_folds_accents_case_and_punctuation() {
+        assert_eq!(normalize("Côte d’Ivoire"), "cote d ivoire");
+        assert_eq!(normalize("  cote d'ivoire "), "cote d ivoire");
+        assert_eq!(normalize("Guinea-Bissau"), "guinea bissau");
+        assert_eq!(normalize("São Tomé and Príncipe"), "sao tome and principe");
+        assert_eq!(normalize("--"), "");
+    }
+
+    #[test]
+    fn collation_key_ignores_case_and_accents_only() {
+        assert_eq!(collation_key("Curaçao"), collation_key("CURACAO"));
+        assert_eq!(collation_key("Côte d'Ivoire"), "cote d'ivoire");
+        assert_ne!(collation_key("Guinea-Bissau"), collation_key("Guinea Bissau"));
+    }
+
+    #[test]
+    fn aliases_drop_blanks_and_duplicates() {
+        let alt_spellings = vec![
+            "CI".to_string(),
+            "cote d'ivoire".to_string(),
+            " ".to_string(),
+            "Ivory Coast".to_string(),
+            "ivory-coast".to_string(),
+        ];
+        assert_eq!(
+            aliases("Côte d'Ivoire", &alt_spellings),
+            ["CI", "Ivory Coast"]
+        );
+    }
+
+    #[test]
+    fn suggests_close_names_first() {
+        let candidates = candidates(&["Nigeria", "Niger", "Ghana", "Guinea"]);
+        // A swapped pair of letters is a single edit
+        assert_eq!(suggest("Nigeira", &candidates), ["Nigeria", "Niger"]);
+        assert_eq!(suggest("Gahna", &candidates), ["Ghana"]);
+    }
+
+    #[test]
+    fn suggests_prefix_matches() {
+        let candidates = candidates(&[
+            "United Kingdom",
+            "United States",
+            "United Arab Emirates",
+            "Uganda",
+        ]);
+        assert_eq!(
+            suggest("united", &candidates),
+            ["United States", "United Kingdom", "United Arab Emirates"]
+        );
+    }
+
+    #[test]
+    fn suggests_each_country_once_and_nothing_for_blank() {
+        let candidates = vec![
+            ("Côte d'Ivoire".to_string(), normalize("Côte d'Ivoire")),
+            ("Côte d'Ivoire".to_string(), normalize("Ivory Coast")),
+        ];
+        assert_eq!(suggest("cote divoire", &candidates), ["Côte d'Ivoire"]);
+        assert!(suggest("  ", &candidates).is_empty());
+        assert!(suggest("Atlantis", &candidates).is_empty());
+    }
+}
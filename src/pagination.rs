@@ -0,0 +1,428 @@
+use crate::error::AppError;
+use crate::gdp::fnv1a;
+use crate::validation::invalid;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// Query parameters that pick the page rather than the result set.
+const PAGE_PARAMS: &[&str] = &["limit", "offset", "cursor"];
+
+/// A window into an ordered result set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    pub limit: i64,
+    pub start: PageStart,
+}
+
+/// Where a page starts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PageStart {
+    /// `offset` rows into the result set, as given by the `offset` parameter.
+    Offset(i64),
+    /// Right after the row at this position.
+    After(Keyset),
+    /// Right before the row at this position; the page is read backwards.
+    Before(Keyset),
+    /// The last `limit` rows.
+    Last,
+}
+
+/// A row's place in the sort order: its value for each sort key, as text,
+/// followed by its `id`, which breaks ties.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyset {
+    pub values: Vec<Option<String>>,
+    pub id: i32,
+}
+
+/// What a cursor carries. Serialised as JSON and base64url-encoded so
+/// clients treat it as opaque.
+///
+/// Pages after the first start from a keyset position rather than an offset,
+/// so rows added or removed between two requests neither shift nor repeat
+/// the rows that follow. `query` ties the cursor to the sort and filters it
+/// was issued for.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    query: String,
+    limit: i64,
+    start: PageStart,
+}
+
+impl Page {
+    /// Resolves the `limit`, `offset` and `cursor` query parameters.
+    ///
+    /// Returns `None` when none of them is given, in which case the caller
+    /// returns every row as a bare array. An explicit `limit` overrides the
+    /// one stored in the cursor. A `limit` outside 1..=`MAX_PAGE_SIZE` or a
+    /// negative `offset` is rejected rather than clamped, and so is a cursor
+    /// issued for another `query` (see `fingerprint`).
+    pub fn from_params(
+        limit: Option<i64>,
+        offset: Option<i64>,
+        cursor: Option<&str>,
+        query: &str,
+    ) -> Result<Option<Page>, AppError> {
+        let from_cursor = match cursor {
+            Some(cursor) => {
+                if offset.is_some() {
+                    return Err(invalid("cursor", "cannot be combined with 'offset'"));
+                }
+                let cursor = decode_cursor(cursor)
+                    .filter(|c| (1..=MAX_PAGE_SIZE).contains(&c.limit))
+                    .filter(|c| !matches!(c.start, PageStart::Offset(offset) if offset < 0))
+                    .ok_or_else(|| invalid("cursor", "is not a valid cursor"))?;
+                if cursor.query != query {
+                    return Err(invalid(
+                        "cursor",
+                        "was issued for a different sort or filters; start again without it",
+                    ));
+                }
+                Some(cursor)
+            }
+            None => None,
+        };
+        if limit.is_none() && offset.is_none() && from_cursor.is_none() {
+            return Ok(None);
+        }
+
+        let mut errors = HashMap::new();
+        if let Some(limit) = limit.filter(|limit| !(1..=MAX_PAGE_SIZE).contains(limit)) {
+            errors.insert(
+                "limit".to_string(),
+                format!("must be between 1 and {} (got {})", MAX_PAGE_SIZE, limit),
+            );
+        }
+        if let Some(offset) = offset.filter(|offset| *offset < 0) {
+            errors.insert(
+                "offset".to_string(),
+                format!("must not be negative (got {})", offset),
+            );
+        }
+        if !errors.is_empty() {
+            return Err(AppError::ValidationError(errors));
+        }
+
+        Ok(Some(match from_cursor {
+            Some(cursor) => Page {
+                limit: limit.unwrap_or(cursor.limit),
+                start: cursor.start,
+            },
+            None => Page {
+                limit: limit.unwrap_or(DEFAULT_PAGE_SIZE),
+                start: PageStart::Offset(offset.unwrap_or(0)),
+            },
+        }))
+    }
+
+    /// Whether the page is read against the sort order.
+    pub fn backwards(&self) -> bool {
+        matches!(self.start, PageStart::Before(_) | PageStart::Last)
+    }
+
+    /// The pages around this one, given the positions of its first and last
+    /// rows and whether more rows lie beyond it in the direction it was read.
+    pub fn links(&self, rows: Option<(Keyset, Keyset)>, more: bool) -> PageLinks {
+        let page = |start| Page {
+            limit: self.limit,
+            start,
+        };
+        let (prev, next) = match (&self.start, rows) {
+            (PageStart::Offset(0), Some((_, last))) => {
+                (None, more.then_some(PageStart::After(last)))
+            }
+            (PageStart::Offset(_) | PageStart::After(_), Some((first, last))) => (
+                Some(PageStart::Before(first)),
+                more.then_some(PageStart::After(last)),
+            ),
+            (PageStart::Before(_), Some((first, last))) => (
+                more.then_some(PageStart::Before(first)),
+                Some(PageStart::After(last)),
+            ),
+            (PageStart::Last, Some((first, _))) => (more.then_some(PageStart::Before(first)), None),
+            // Past the end: the last page comes before it
+            (PageStart::Offset(offset), None) if *offset > 0 => (Some(PageStart::Last), None),
+            (PageStart::After(_), None) => (Some(PageStart::Last), None),
+            // Before the start, or nothing matches at all
+            (_, None) => (None, None),
+        };
+        PageLinks {
+            first: page(PageStart::Offset(0)),
+            prev: prev.map(page),
+            next: next.map(page),
+            last: page(PageStart::Last),
+        }
+    }
+
+    /// This page as a cursor for the query with fingerprint `query`.
+    pub fn cursor(&self, query: &str) -> String {
+        let cursor = Cursor {
+            query: query.to_string(),
+            limit: self.limit,
+            start: self.start.clone(),
+        };
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&cursor).expect("cursor serialises"))
+    }
+}
+
+/// The pages a `Link` header points to.
+#[derive(Debug, PartialEq)]
+pub struct PageLinks {
+    pub first: Page,
+    pub prev: Option<Page>,
+    pub next: Option<Page>,
+    pub last: Page,
+}
+
+fn decode_cursor(cursor: &str) -> Option<Cursor> {
+    let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Identifies the result set a query string asks for: a hash of its
+/// parameters other than `limit`, `offset` and `cursor`, in any order.
+pub fn fingerprint(params: &[(String, String)]) -> String {
+    let mut params: Vec<String> = params
+        .iter()
+        .filter(|(key, _)| !PAGE_PARAMS.contains(&key.as_str()))
+        .map(|pair| serde_urlencoded::to_string([pair]).unwrap_or_default())
+        .collect();
+    params.sort();
+    format!("{:016x}", fnv1a(params.join("&").as_bytes()))
+}
+
+/// Builds an RFC 8288 `Link` header value with `first`, `prev`, `next` and
+/// `last` relations. Each target is `path` with the original query string,
+/// its `limit`/`offset`/`cursor` replaced by the cursor of that page for the
+/// query with fingerprint `query`.
+pub fn link_header(path: &str, query_string: &str, query: &str, links: &PageLinks) -> String {
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(query_string).unwrap_or_default();
+    let params: Vec<(String, String)> = params
+        .into_iter()
+        .filter(|(key, _)| !PAGE_PARAMS.contains(&key.as_str()))
+        .collect();
+
+    let link = |target: &Page, rel: &str| {
+        let mut params = params.clone();
+        params.push(("cursor".to_string(), target.cursor(query)));
+        let query = serde_urlencoded::to_string(&params).unwrap_or_default();
+        format!("<{}?{}>; rel=\"{}\"", path, query, rel)
+    };
+
+    let mut header = vec![link(&links.first, "first")];
+    if let Some(prev) = &links.prev {
+        header.push(link(prev, "prev"));
+    }
+    if let Some(next) = &links.next {
+        header.push(link(next, "next"));
+    }
+    header.push(link(&links.last, "last"));
+    header.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = "0123456789abcdef";
+
+    fn page(limit: i64, start: PageStart) -> Page {
+        Page { limit, start }
+    }
+
+    fn keyset(id: i32) -> Keyset {
+        Keyset {
+            values: vec![Some(format!("Country {}", id)), None],
+            id,
+        }
+    }
+
+    fn error_keys(result: Result<Option<Page>, AppError>) -> Vec<String> {
+        match result {
+            Err(AppError::ValidationError(details)) => {
+                let mut keys: Vec<String> = details.into_keys().collect();
+                keys.sort();
+                keys
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn no_params_means_no_page() {
+        assert_eq!(Page::from_params(None, None, None, QUERY).unwrap(), None);
+    }
+
+    #[test]
+    fn defaults_fill_missing_params() {
+        assert_eq!(
+            Page::from_params(None, Some(40), None, QUERY).unwrap(),
+            Some(page(DEFAULT_PAGE_SIZE, PageStart::Offset(40)))
+        );
+        assert_eq!(
+            Page::from_params(Some(5), None, None, QUERY).unwrap(),
+            Some(page(5, PageStart::Offset(0)))
+        );
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let after = page(10, PageStart::After(keyset(7)));
+        let cursor = after.cursor(QUERY);
+        assert_eq!(
+            Page::from_params(None, None, Some(&cursor), QUERY).unwrap(),
+            Some(after)
+        );
+        // An explicit limit wins over the cursor's
+        assert_eq!(
+            Page::from_params(Some(25), None, Some(&cursor), QUERY).unwrap(),
+            Some(page(25, PageStart::After(keyset(7))))
+        );
+    }
+
+    #[test]
+    fn cursor_is_tied_to_its_query() {
+        let cursor = page(10, PageStart::Last).cursor(QUERY);
+        assert_eq!(
+            error_keys(Page::from_params(
+                None,
+                None,
+                Some(&cursor),
+                "fedcba9876543210"
+            )),
+            ["cursor"]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_params() {
+        assert_eq!(
+            error_keys(Page::from_params(Some(0), None, None, QUERY)),
+            ["limit"]
+        );
+        assert_eq!(
+            error_keys(Page::from_params(
+                Some(MAX_PAGE_SIZE + 1),
+                None,
+                None,
+                QUERY
+            )),
+            ["limit"]
+        );
+        assert_eq!(
+            error_keys(Page::from_params(Some(5000), Some(-3), None, QUERY)),
+            ["limit", "offset"]
+        );
+        assert_eq!(
+            error_keys(Page::from_params(None, None, Some("not a cursor"), QUERY)),
+            ["cursor"]
+        );
+        let cursor = page(10, PageStart::Offset(30)).cursor(QUERY);
+        assert_eq!(
+            error_keys(Page::from_params(None, Some(0), Some(&cursor), QUERY)),
+            ["cursor"]
+        );
+    }
+
+    #[test]
+    fn fingerprint_ignores_page_params_and_order() {
+        let params =
+            |query: &str| -> Vec<(String, String)> { serde_urlencoded::from_str(query).unwrap() };
+        assert_eq!(
+            fingerprint(&params("region=Africa&sort=-population&limit=5")),
+            fingerprint(&params("sort=-population&cursor=abc&region=Africa"))
+        );
+        assert_ne!(
+            fingerprint(&params("region=Africa&sort=-population")),
+            fingerprint(&params("region=Africa&sort=population"))
+        );
+        assert_ne!(
+            fingerprint(&params("region=Africa")),
+            fingerprint(&params("region=Asia"))
+        );
+    }
+
+    #[test]
+    fn links_read_forwards() {
+        let first = page(10, PageStart::Offset(0));
+        let rows = Some((keyset(1), keyset(10)));
+        let links = first.links(rows.clone(), true);
+        assert_eq!(links.first, first);
+        assert_eq!(links.prev, None);
+        assert_eq!(links.next, Some(page(10, PageStart::After(keyset(10)))));
+        assert_eq!(links.last, page(10, PageStart::Last));
+
+        let middle = page(10, PageStart::After(keyset(10)));
+        let links = middle.links(Some((keyset(11), keyset(20))), false);
+        assert_eq!(links.prev, Some(page(10, PageStart::Before(keyset(11)))));
+        assert_eq!(links.next, None);
+    }
+
+    #[test]
+    fn links_read_backwards() {
+        let before = page(10, PageStart::Before(keyset(11)));
+        assert!(before.backwards());
+        let links = before.links(Some((keyset(1), keyset(10))), false);
+        assert_eq!(links.prev, None);
+        assert_eq!(links.next, Some(page(10, PageStart::After(keyset(10)))));
+
+        let last = page(10, PageStart::Last);
+        let links = last.links(Some((keyset(21), keyset(25))), true);
+        assert_eq!(links.prev, Some(page(10, PageStart::Before(keyset(21)))));
+        assert_eq!(links.next, None);
+    }
+
+    #[test]
+    fn links_past_the_end_lead_back() {
+        let links = page(10, PageStart::Offset(500)).links(None, false);
+        assert_eq!(links.prev, Some(page(10, PageStart::Last)));
+        assert_eq!(links.next, None);
+        let links = page(10, PageStart::Offset(0)).links(None, false);
+        assert_eq!(links.prev, None);
+        assert_eq!(links.next, None);
+    }
+
+    #[test]
+    fn link_header_keeps_other_params() {
+        let links = page(10, PageStart::Offset(10)).links(Some((keyset(11), keyset(20))), true);
+        let header = link_header(
+            "/countries",
+            "region=Africa&limit=10&offset=10",
+            QUERY,
+            &links,
+        );
+        let link = |target: &Page, rel: &str| {
+            format!(
+                "</countries?region=Africa&cursor={}>; rel=\"{}\"",
+                target.cursor(QUERY),
+                rel
+            )
+        };
+        assert_eq!(
+            header.split(", ").collect::<Vec<&str>>(),
+            [
+                link(&page(10, PageStart::Offset(0)), "first"),
+                link(&page(10, PageStart::Before(keyset(11))), "prev"),
+                link(&page(10, PageStart::After(keyset(20))), "next"),
+                link(&page(10, PageStart::Last), "last"),
+            ]
+        );
+    }
+
+    #[test]
+    fn link_header_on_a_single_page() {
+        let links = page(10, PageStart::Offset(0)).links(Some((keyset(1), keyset(3))), false);
+        let header = link_header("/countries", "limit=10", QUERY, &links);
+        assert!(header.contains("rel=\"first\""));
+        assert!(header.contains("rel=\"last\""));
+        assert!(!header.contains("rel=\"prev\""));
+        assert!(!header.contains("rel=\"next\""));
+    }
+}
@@ -1,50 +1,132 @@
 use crate::db;
 use crate::error::AppError;
-use crate::external;
+use crate::config::RefreshSettings;
+use crate::db::RefreshOptions;
+use crate::external::DataSources;
+use crate::gdp::GdpEstimator;
 use crate::image;
-use crate::models::{GetCountriesQuery, RefreshResponse};
+use crate::pagination;
+use crate::validation;
+use crate::models::{
+    CompareCountriesQuery, ConversionResponse, ConvertQuery, CountryPage, CountryPayload, ListRefreshRunsQuery, RateHistoryQuery, RateHistoryResponse,
+    RefreshQuery, RefreshResponse, RefreshRunSummary, SearchCountriesQuery, StatusResponse,
+};
 
 use actix_files::NamedFile;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use sqlx::MySqlPool;
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
 /// Shared application state
 pub struct AppState {
     pub db_pool: MySqlPool,
-    pub http_client: reqwest::Client,
+    pub sources: DataSources,
+    pub gdp_estimator: Arc<dyn GdpEstimator>,
+    pub refresh_settings: RefreshSettings,
 }
 
 /// Configures all API routes
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/countries")
+            // Malformed bodies get the same 400 as invalid fields
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                validation::invalid("body", &err.to_string()).into()
+            }))
             .route("/refresh", web::post().to(refresh_countries))
             .route("", web::get().to(get_countries))
+            .route("", web::post().to(create_country))
             .route("/image", web::get().to(serve_summary_image))
-            .route("/{name}", web::get().to(get_country))
-            .route("/{name}", web::delete().to(delete_country)),
+            .route("/search", web::get().to(search_countries))
+            // Before `/{id}`, which would otherwise take "compare" for a name
+            .route("/compare", web::get().to(compare_countries))
+            .route("/{id}", web::get().to(get_country))
+            .route("/{id}", web::put().to(replace_country))
+            .route("/{id}", web::patch().to(update_country))
+            .route("/{id}", web::delete().to(delete_country))
+            .route("/{id}/restore", web::post().to(restore_country)),
     )
+    .service(
+        web::scope("/currencies")
+            .route("", web::get().to(get_currencies))
+            .route("/{code}", web::get().to(get_currency))
+            .route("/{code}/history", web::get().to(get_rate_history)),
+    )
+    .service(
+        web::scope("/regions")
+            .route("", web::get().to(get_regions))
+            .route("/{region}", web::get().to(get_region)),
+    )
+    .service(
+        web::scope("/refresh")
+            .route("/runs", web::get().to(get_refresh_runs))
+            .route("/runs/{id}", web::get().to(get_refresh_run)),
+    )
+    .route("/convert", web::get().to(convert_currency))
     .route("/status", web::get().to(get_status));
 }
 
 /// POST /countries/refresh
 /// Fetches new data, refreshes the DB, and generates the summary image.
+/// Every call is recorded in `refresh_runs`, whether it succeeds or fails.
+/// Manual overrides are left alone unless `?force=true`.
 async fn refresh_countries(
     state: web::Data<AppState>,
+    query: web::Query<RefreshQuery>,
 ) -> Result<impl Responder, AppError> {
     log::info!("Starting data refresh...");
 
-    // 1. Fetch data from external APIs
-    let (countries_res, rates_res) = tokio::try_join!(
-        external::fetch_countries(&state.http_client),
-        external::fetch_exchange_rates(&state.http_client)
-    )?;
+    let started = Instant::now();
+    let run_id = db::start_refresh_run(&state.db_pool, Utc::now()).await?;
+    let mut summary = RefreshRunSummary::default();
+
+    let result = run_refresh(&state, run_id, query.force, &mut summary).await;
+
+    summary.finished_at = Some(Utc::now());
+    summary.duration_ms = started.elapsed().as_millis() as i64;
+    if let Err(e) = &result {
+        summary.failure_reason = Some(e.report());
+    }
+    if let Err(e) = db::finish_refresh_run(&state.db_pool, run_id, &summary).await {
+        log::error!("Failed to record refresh run {}: {}", run_id, e.report());
+    }
+
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+/// The refresh itself; fills in `summary` as it goes so that a failed run
+/// still records what it got through.
+async fn run_refresh(
+    state: &AppState,
+    run_id: i32,
+    force: bool,
+    summary: &mut RefreshRunSummary,
+) -> Result<RefreshResponse, AppError> {
+    // 1. Fetch data from the configured sources
+    let (countries_res, rates_res) = tokio::join!(
+        state.sources.fetch_countries(),
+        state.sources.fetch_rates()
+    );
+    if let Ok(countries) = &countries_res {
+        summary.country_provider = Some(countries.provider.clone());
+    }
+    if let Ok(rates) = &rates_res {
+        summary.rate_provider = Some(rates.provider.clone());
+    }
+    let (countries_res, rates_res) = (countries_res?, rates_res?);
 
     log::info!(
-        "Fetched {} countries and {} exchange rates",
-        countries_res.len(),
-        rates_res.rates.len()
+        "Fetched {} countries from {} and {} exchange rates from {}",
+        countries_res.countries.len(),
+        countries_res.provider,
+        rates_res.rates.len(),
+        rates_res.provider
     );
 
     /*// For testing `If currency_code is not found in the exchange rates API:`
@@ -53,38 +135,229 @@ async fn refresh_countries(
     */
 
     // 2. Process and save data to DB
-    let (status, top_countries) =
-        db::refresh_data(&state.db_pool, countries_res, rates_res.rates).await?;
+    let options = RefreshOptions {
+        run_id,
+        estimator: state.gdp_estimator.as_ref(),
+        stale_policy: state.refresh_settings.stale_policy,
+        batch_size: state.refresh_settings.batch_size,
+        force,
+    };
+    let outcome =
+        db::refresh_data(&state.db_pool, countries_res.countries, &rates_res, &options).await?;
+    summary.stats = outcome.stats.clone();
 
     log::info!(
-        "Database refresh complete. {} countries processed.",
-        status.total_countries
+        "Database refresh complete. {} countries processed ({} inserted, {} updated, {} unchanged, {} removed, {} manual overrides skipped).",
+        outcome.stats.inserted + outcome.stats.updated + outcome.stats.unchanged,
+        outcome.stats.inserted,
+        outcome.stats.updated,
+        outcome.stats.unchanged,
+        outcome.stats.removed,
+        outcome.stats.skipped
     );
 
     // 3. Generate summary image
-    image::generate_summary_image(&status, &top_countries)?;
+    image::generate_summary_image(&outcome.status, &outcome.top_countries)?;
 
-    let response = RefreshResponse {
+    Ok(RefreshResponse {
         status: "success".to_string(),
-        countries_processed: status.total_countries as usize,
-        last_refreshed_at: status.last_refreshed_at.unwrap_or_else(chrono::Utc::now),
-    };
+        run_id,
+        countries_processed: (outcome.stats.inserted
+            + outcome.stats.updated
+            + outcome.stats.unchanged) as usize,
+        last_refreshed_at: outcome.status.last_refreshed_at.unwrap_or_else(Utc::now),
+        country_provider: countries_res.provider,
+        rate_provider: rates_res.provider,
+        changes: outcome.stats,
+        stale_policy: options.stale_policy.as_str().to_string(),
+        stale_countries: outcome.stale_countries,
+    })
+}
+
+/// GET /currencies
+/// Lists every currency used by a country, with its latest rate and totals.
+async fn get_currencies(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let currencies = db::get_currencies(&state.db_pool, None).await?;
+    Ok(HttpResponse::Ok().json(currencies))
+}
+
+/// GET /currencies/:code
+/// Retrieves a single currency by its ISO 4217 code.
+async fn get_currency(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, AppError> {
+    let code = path.into_inner().to_uppercase();
+    let currency = db::get_currencies(&state.db_pool, Some(&code))
+        .await?
+        .pop()
+        .ok_or_else(|| AppError::NotFound(format!("Currency '{}' not found", code)))?;
+    Ok(HttpResponse::Ok().json(currency))
+}
+
+/// GET /regions
+/// Lists every region with aggregate country statistics.
+async fn get_regions(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let regions = db::get_regions(&state.db_pool, None).await?;
+    Ok(HttpResponse::Ok().json(regions))
+}
+
+/// GET /regions/:region
+/// Retrieves the statistics of a single region.
+async fn get_region(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, AppError> {
+    let region = path.into_inner();
+    let summary = db::get_regions(&state.db_pool, Some(&region))
+        .await?
+        .pop()
+        .ok_or_else(|| AppError::NotFound(format!("Region '{}' not found", region)))?;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// GET /currencies/:code/history
+/// Returns the stored USD exchange rates of a currency over time.
+async fn get_rate_history(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<RateHistoryQuery>,
+) -> Result<impl Responder, AppError> {
+    let code = path.into_inner().to_uppercase();
+    let RateHistoryQuery { from, to } = query.into_inner();
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            let mut details = HashMap::new();
+            details.insert("from".to_string(), "must not be after 'to'".to_string());
+            return Err(AppError::ValidationError(details));
+        }
+    }
 
-    Ok(HttpResponse::Ok().json(response))
+    let points = db::get_rate_history(&state.db_pool, &code, from, to).await?;
+    Ok(HttpResponse::Ok().json(RateHistoryResponse {
+        currency_code: code,
+        from,
+        to,
+        points,
+    }))
+}
+
+/// GET /convert
+/// Converts an amount between two currencies through their latest cached USD rates.
+async fn convert_currency(
+    state: web::Data<AppState>,
+    query: web::Query<ConvertQuery>,
+) -> Result<impl Responder, AppError> {
+    let validation::Conversion { from, to, amount } = validation::conversion(&query)?;
+    let (from_usd_rate, to_usd_rate) = tokio::try_join!(
+        db::get_latest_rate(&state.db_pool, &from),
+        db::get_latest_rate(&state.db_pool, &to)
+    )?;
+
+    // Stored rates are units per USD and never zero
+    let converted = amount
+        .checked_mul(to_usd_rate.rate)
+        .and_then(|value| value.checked_div(from_usd_rate.rate))
+        .ok_or_else(|| validation::invalid("amount", "is too large to convert"))?;
+    let rate = to_usd_rate.rate / from_usd_rate.rate;
+
+    Ok(HttpResponse::Ok().json(ConversionResponse {
+        from,
+        to,
+        amount,
+        converted: converted.round_dp(6),
+        rate: rate.round_dp(10),
+        rate_fetched_at: from_usd_rate.fetched_at.min(to_usd_rate.fetched_at),
+        from_usd_rate,
+        to_usd_rate,
+    }))
+}
+
+/// GET /refresh/runs
+/// Lists past refresh runs, newest first.
+async fn get_refresh_runs(
+    state: web::Data<AppState>,
+    query: web::Query<ListRefreshRunsQuery>,
+) -> Result<impl Responder, AppError> {
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let runs = db::get_refresh_runs(&state.db_pool, limit, offset).await?;
+    Ok(HttpResponse::Ok().json(runs))
+}
+
+/// GET /refresh/runs/:id
+/// Retrieves a single refresh run.
+async fn get_refresh_run(
+    state: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<impl Responder, AppError> {
+    let run = db::get_refresh_run(&state.db_pool, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(run))
 }
 
 /// GET /countries
 /// Retrieves a list of all countries, with optional filters.
+/// Paginated requests get a `CountryPage` and a `Link` header; others the bare array.
 async fn get_countries(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, AppError> {
+    let listing = validation::country_listing(req.query_string())?;
+    let (filters, sort) = (&listing.filters, listing.sort.as_slice());
+    let Some(page) = listing.page else {
+        let countries = db::get_all_countries(&state.db_pool, filters, sort).await?;
+        return Ok(HttpResponse::Ok().json(countries));
+    };
+
+    let total = db::count_countries(&state.db_pool, filters).await?;
+    let (items, more) = db::get_country_page(&state.db_pool, filters, sort, &page).await?;
+    let rows = match (items.first(), items.last()) {
+        (Some(first), Some(last)) => Some((
+            db::sort_position(first, sort),
+            db::sort_position(last, sort),
+        )),
+        _ => None,
+    };
+    let links = page.links(rows, more);
+    let fingerprint = &listing.fingerprint;
+    let link = pagination::link_header(req.path(), req.query_string(), fingerprint, &links);
+    Ok(HttpResponse::Ok()
+        .insert_header((header::LINK, link))
+        .json(CountryPage {
+            items,
+            total,
+            next_cursor: links.next.map(|next| next.cursor(fingerprint)),
+        }))
+}
+
+/// GET /countries/search
+/// Ranked name/capital/region search for autocomplete.
+async fn search_countries(
     state: web::Data<AppState>,
-    query: web::Query<GetCountriesQuery>,
+    query: web::Query<SearchCountriesQuery>,
 ) -> Result<impl Responder, AppError> {
-    let countries = db::get_all_countries(&state.db_pool, query.into_inner()).await?;
+    let q = query.q.as_deref().map(str::trim).unwrap_or_default();
+    if q.is_empty() {
+        return Err(validation::invalid("q", "is required"));
+    }
+    let limit = query.limit.unwrap_or(10).clamp(1, 50);
+    let countries = db::search_countries(&state.db_pool, q, limit).await?;
     Ok(HttpResponse::Ok().json(countries))
 }
 
-/// GET /countries/:name
-/// Retrieves a single country by its name.
+/// GET /countries/compare
+/// Returns the named countries side by side with derived metrics.
+async fn compare_countries(
+    state: web::Data<AppState>,
+    query: web::Query<CompareCountriesQuery>,
+) -> Result<impl Responder, AppError> {
+    let names = validation::compared_names(query.names.as_deref())?;
+    let comparison = db::compare_countries(&state.db_pool, &names).await?;
+    Ok(HttpResponse::Ok().json(comparison))
+}
+
+/// GET /countries/:id
+/// Retrieves a single country by its name or ISO alpha-2/alpha-3 code.
 async fn get_country(
     state: web::Data<AppState>,
     path: web::Path<String>,
@@ -94,8 +367,58 @@ async fn get_country(
     Ok(HttpResponse::Ok().json(country))
 }
 
-/// DELETE /countries/:name
-/// Deletes a single country by its name.
+/// POST /countries
+/// Adds a country by hand. It is marked as a manual override.
+async fn create_country(
+    state: web::Data<AppState>,
+    body: web::Json<CountryPayload>,
+) -> Result<impl Responder, AppError> {
+    let record = validation::country_payload(body.into_inner(), None)?;
+    let country =
+        db::save_manual_country(&state.db_pool, None, record, state.gdp_estimator.as_ref()).await?;
+    let location = format!(
+        "/countries/{}",
+        utf8_percent_encode(&country.name, NON_ALPHANUMERIC)
+    );
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .json(country))
+}
+
+/// PUT /countries/:id
+/// Replaces a country; fields left out of the body are cleared.
+/// It is marked as a manual override.
+async fn replace_country(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<CountryPayload>,
+) -> Result<impl Responder, AppError> {
+    let (id, _) = db::get_country_record(&state.db_pool, &path.into_inner()).await?;
+    let record = validation::country_payload(body.into_inner(), None)?;
+    let country =
+        db::save_manual_country(&state.db_pool, Some(id), record, state.gdp_estimator.as_ref())
+            .await?;
+    Ok(HttpResponse::Ok().json(country))
+}
+
+/// PATCH /countries/:id
+/// Updates the fields given in the body and keeps the rest.
+/// It is marked as a manual override.
+async fn update_country(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<CountryPayload>,
+) -> Result<impl Responder, AppError> {
+    let (id, stored) = db::get_country_record(&state.db_pool, &path.into_inner()).await?;
+    let record = validation::country_payload(body.into_inner(), Some(stored))?;
+    let country =
+        db::save_manual_country(&state.db_pool, Some(id), record, state.gdp_estimator.as_ref())
+            .await?;
+    Ok(HttpResponse::Ok().json(country))
+}
+
+/// DELETE /countries/:id
+/// Soft-deletes a single country by its name or ISO alpha-2/alpha-3 code.
 async fn delete_country(
     state: web::Data<AppState>,
     path: web::Path<String>,
@@ -105,11 +428,28 @@ async fn delete_country(
     Ok(HttpResponse::NoContent().finish())
 }
 
+/// POST /countries/:id/restore
+/// Brings back a deleted country.
+async fn restore_country(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, AppError> {
+    let name = path.into_inner();
+    let country = db::restore_country_by_name(&state.db_pool, &name).await?;
+    Ok(HttpResponse::Ok().json(country))
+}
+
 /// GET /status
-/// Returns the total country count and last refresh time.
+/// Returns the total country count, last refresh time and upstream breaker states.
 async fn get_status(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
     let status = db::get_app_status(&state.db_pool).await?;
-    Ok(HttpResponse::Ok().json(status))
+    let upstreams = state
+        .sources
+        .breakers
+        .iter()
+        .map(|breaker| breaker.snapshot())
+        .collect();
+    Ok(HttpResponse::Ok().json(StatusResponse { status, upstreams }))
 }
 
 /// GET /countries/image
@@ -0,0 +1,610 @@
+use crate::db::{LEGACY_SORTS, SORT_FIELDS};
+use crate::error::AppError;
+use crate::models::{
+    ConvertQuery, CountryFilters, CountryPayload, CountryRecord, CurrencyInfo, CurrencyPayload,
+    GetCountriesQuery, SortKey,
+};
+use crate::pagination::{self, Page};
+use sqlx::types::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// Query parameters accepted by `GET /countries`.
+pub const COUNTRY_QUERY_PARAMS: &[&str] = &[
+    "region",
+    "currency",
+    "sort",
+    "limit",
+    "offset",
+    "cursor",
+    "min_population",
+    "max_population",
+    "min_gdp",
+    "max_gdp",
+    "min_rate",
+    "max_rate",
+    "include_deleted",
+];
+
+/// A `GET /countries` request that passed validation.
+#[derive(Debug)]
+pub struct CountryListing {
+    pub filters: CountryFilters,
+    pub sort: Vec<SortKey>,
+    pub page: Option<Page>,
+    /// Ties cursors to this sort and these filters; see `pagination::fingerprint`.
+    pub fingerprint: String,
+}
+
+/// Parses and checks the query string of `GET /countries`.
+///
+/// Unknown or repeated parameters, unknown sort keys and malformed values are
+/// all reported together in a single `ValidationError`.
+pub fn country_listing(query_string: &str) -> Result<CountryListing, AppError> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query_string)
+        .map_err(|e| invalid("query", &format!("is not a valid query string: {}", e)))?;
+
+    let mut errors = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut repeated = false;
+    for (key, _) in &pairs {
+        if !COUNTRY_QUERY_PARAMS.contains(&key.as_str()) {
+            errors.insert(
+                key.clone(),
+                format!("unknown parameter; allowed: {}", COUNTRY_QUERY_PARAMS.join(", ")),
+            );
+        } else if !seen.insert(key.as_str()) {
+            errors.insert(key.clone(), "must be given only once".to_string());
+            repeated = true;
+        }
+    }
+    // Repeated keys would fail deserialisation below; unknown ones are ignored by it
+    if repeated {
+        return Err(AppError::ValidationError(errors));
+    }
+
+    let query: GetCountriesQuery = serde_urlencoded::from_str(query_string)
+        .map_err(|e| invalid("query", &format!("is not a valid query string: {}", e)))?;
+
+    let sort = match query.sort.as_deref().map(sort_keys) {
+        Some(Ok(keys)) => keys,
+        Some(Err(message)) => {
+            errors.insert("sort".to_string(), message);
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+
+    let limit = parse::<i64>(&mut errors, "limit", &query.limit, "an integer");
+    let offset = parse::<i64>(&mut errors, "offset", &query.offset, "an integer");
+    let filters = country_filters(&query, &mut errors);
+    let fingerprint = pagination::fingerprint(&pairs);
+    let page = match Page::from_params(limit, offset, query.cursor.as_deref(), &fingerprint) {
+        Ok(page) => page,
+        Err(AppError::ValidationError(details)) => {
+            errors.extend(details);
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    if !errors.is_empty() {
+        return Err(AppError::ValidationError(errors));
+    }
+    Ok(CountryListing {
+        filters,
+        sort,
+        page,
+        fingerprint,
+    })
+}
+
+/// Parses a `sort` value such as `region,-estimated_gdp,name`: a comma-separated
+/// list of `SORT_FIELDS`, each descending when prefixed with `-`. The legacy
+/// values (`gdp_desc`, ...) are accepted as aliases.
+fn sort_keys(sort: &str) -> Result<Vec<SortKey>, String> {
+    let sort = LEGACY_SORTS
+        .iter()
+        .find(|(alias, _)| *alias == sort)
+        .map_or(sort, |(_, keys)| *keys);
+
+    let mut keys: Vec<SortKey> = Vec::new();
+    for term in sort.split(',') {
+        // A leading `+` arrives as a space once the query string is decoded
+        let term = term.trim();
+        let (field, descending) = match term.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (term.strip_prefix('+').unwrap_or(term), false),
+        };
+        let Some(&(_, column)) = SORT_FIELDS.iter().find(|(name, _)| *name == field) else {
+            let fields: Vec<&str> = SORT_FIELDS.iter().map(|(name, _)| *name).collect();
+            let aliases: Vec<&str> = LEGACY_SORTS.iter().map(|(alias, _)| *alias).collect();
+            return Err(format!(
+                "unknown sort field '{}'; allowed: {} (prefix with '-' for descending), or one of {}",
+                term,
+                fields.join(", "),
+                aliases.join(", ")
+            ));
+        };
+        if keys.iter().any(|key| key.column == column) {
+            return Err(format!("sort field '{}' is listed more than once", field));
+        }
+        keys.push(SortKey { column, descending });
+    }
+    Ok(keys)
+}
+
+/// Parses the filter parameters, recording malformed or inverted bounds in `errors`.
+fn country_filters(
+    query: &GetCountriesQuery,
+    errors: &mut HashMap<String, String>,
+) -> CountryFilters {
+    let min_population = parse::<i64>(errors, "min_population", &query.min_population, "an integer");
+    let max_population = parse::<i64>(errors, "max_population", &query.max_population, "an integer");
+    let min_gdp = parse::<Decimal>(errors, "min_gdp", &query.min_gdp, "a number");
+    let max_gdp = parse::<Decimal>(errors, "max_gdp", &query.max_gdp, "a number");
+    let min_rate = parse::<Decimal>(errors, "min_rate", &query.min_rate, "a number");
+    let max_rate = parse::<Decimal>(errors, "max_rate", &query.max_rate, "a number");
+
+    check_order(errors, ("min_population", min_population), ("max_population", max_population));
+    check_order(errors, ("min_gdp", min_gdp), ("max_gdp", max_gdp));
+    check_order(errors, ("min_rate", min_rate), ("max_rate", max_rate));
+    let include_deleted = parse::<bool>(errors, "include_deleted", &query.include_deleted, "true or false");
+
+    CountryFilters {
+        region: query.region.clone(),
+        currency: query.currency.clone(),
+        min_population,
+        max_population,
+        min_gdp,
+        max_gdp,
+        min_rate,
+        max_rate,
+        include_deleted: include_deleted.unwrap_or(false),
+    }
+}
+
+/// Parses an optional parameter, recording an error if it is malformed.
+fn parse<T: FromStr>(
+    errors: &mut HashMap<String, String>,
+    key: &str,
+    value: &Option<String>,
+    expected: &str,
+) -> Option<T> {
+    let value = value.as_deref()?;
+    match value.trim().parse::<T>() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            errors.insert(key.to_string(), format!("must be {} (got '{}')", expected, value));
+            None
+        }
+    }
+}
+
+/// Records an error if both bounds are set and `min` is greater than `max`.
+fn check_order<T: PartialOrd>(
+    errors: &mut HashMap<String, String>,
+    (min_key, min): (&str, Option<T>),
+    (max_key, max): (&str, Option<T>),
+) {
+    if let (Some(min), Some(max)) = (min, max) {
+        if min > max {
+            errors.insert(min_key.to_string(), format!("must not be greater than '{}'", max_key));
+        }
+    }
+}
+
+/// Most countries `GET /countries/compare` accepts at once.
+pub const MAX_COMPARED_COUNTRIES: usize = 20;
+
+/// Splits the `names` parameter of `GET /countries/compare` into its
+/// non-blank entries.
+pub fn compared_names(names: Option<&str>) -> Result<Vec<String>, AppError> {
+    let names: Vec<String> = names
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    if names.is_empty() {
+        return Err(invalid("names", "is required"));
+    }
+    if names.len() > MAX_COMPARED_COUNTRIES {
+        return Err(invalid(
+            "names",
+            &format!("must list at most {} countries", MAX_COMPARED_COUNTRIES),
+        ));
+    }
+    Ok(names)
+}
+
+/// Longest `name`, `capital`, `region` and alias the tables hold.
+const MAX_TEXT_LENGTH: usize = 255;
+const MAX_FLAG_URL_LENGTH: usize = 2048;
+
+/// Checks a `POST`/`PUT`/`PATCH /countries` body and turns it into a record.
+///
+/// `base` is the stored country when patching: fields missing from the body
+/// keep their stored values. Without it, `name` and `population` are required
+/// and every other missing field is left empty. All problems are reported
+/// together in a single `ValidationError`.
+pub fn country_payload(
+    payload: CountryPayload,
+    base: Option<CountryRecord>,
+) -> Result<CountryRecord, AppError> {
+    let mut errors = HashMap::new();
+    let partial = base.is_some();
+    let mut record = base.unwrap_or_else(|| CountryRecord {
+        name: String::new(),
+        capital: None,
+        region: None,
+        population: 0,
+        flag_url: None,
+        currencies: Vec::new(),
+        alt_spellings: Vec::new(),
+        alpha2_code: None,
+        alpha3_code: None,
+        numeric_code: None,
+    });
+
+    match payload.name {
+        Some(name) => {
+            if let Some(name) = text(&mut errors, "name", Some(name), MAX_TEXT_LENGTH) {
+                record.name = name;
+            } else {
+                errors.entry("name".to_string()).or_insert_with(|| "must not be blank".to_string());
+            }
+        }
+        None if !partial => {
+            errors.insert("name".to_string(), "is required".to_string());
+        }
+        None => {}
+    }
+    match payload.population {
+        Some(population) if population < 0 => {
+            errors.insert("population".to_string(), "must not be negative".to_string());
+        }
+        Some(population) => record.population = population,
+        None if !partial => {
+            errors.insert("population".to_string(), "is required".to_string());
+        }
+        None => {}
+    }
+
+    if let Some(capital) = payload.capital {
+        record.capital = text(&mut errors, "capital", capital, MAX_TEXT_LENGTH);
+    }
+    if let Some(region) = payload.region {
+        record.region = text(&mut errors, "region", region, MAX_TEXT_LENGTH);
+    }
+    if let Some(flag_url) = payload.flag_url {
+        record.flag_url = text(&mut errors, "flag_url", flag_url, MAX_FLAG_URL_LENGTH);
+    }
+    if let Some(currencies) = payload.currencies {
+        record.currencies = currency_list(&mut errors, currencies);
+    }
+    if let Some(alt_spellings) = payload.alt_spellings {
+        record.alt_spellings = alt_spellings
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, alias)| {
+                text(&mut errors, &format!("alt_spellings[{}]", i), Some(alias), MAX_TEXT_LENGTH)
+            })
+            .collect();
+    }
+    if let Some(code) = payload.alpha2_code {
+        record.alpha2_code = iso_code(&mut errors, "alpha2_code", code, 2, |c| c.is_ascii_alphabetic());
+    }
+    if let Some(code) = payload.alpha3_code {
+        record.alpha3_code = iso_code(&mut errors, "alpha3_code", code, 3, |c| c.is_ascii_alphabetic());
+    }
+    if let Some(code) = payload.numeric_code {
+        record.numeric_code = iso_code(&mut errors, "numeric_code", code, 3, |c| c.is_ascii_digit());
+    }
+
+    if !errors.is_empty() {
+        return Err(AppError::ValidationError(errors));
+    }
+    Ok(record)
+}
+
+/// Checks the currencies of a country body: ISO 4217 codes (three letters,
+/// uppercased here), each listed once.
+fn currency_list(
+    errors: &mut HashMap<String, String>,
+    currencies: Vec<CurrencyPayload>,
+) -> Vec<CurrencyInfo> {
+    let mut seen = HashSet::new();
+    let mut list = Vec::with_capacity(currencies.len());
+    for (i, currency) in currencies.into_iter().enumerate() {
+        let key = format!("currencies[{}].code", i);
+        let Some(code) = currency_code(&currency.code) else {
+            errors.insert(key, format!("must be a three-letter ISO 4217 code (got '{}')", currency.code));
+            continue;
+        };
+        if !seen.insert(code.clone()) {
+            errors.insert(key, format!("currency '{}' is listed more than once", code));
+            continue;
+        }
+        list.push(CurrencyInfo {
+            code,
+            name: text(errors, &format!("currencies[{}].name", i), currency.name, MAX_TEXT_LENGTH),
+            symbol: text(errors, &format!("currencies[{}].symbol", i), currency.symbol, 32),
+        });
+    }
+    list
+}
+
+/// `value` as an uppercase ISO 4217 code, if it is three letters.
+fn currency_code(value: &str) -> Option<String> {
+    let code = value.trim().to_uppercase();
+    (code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic())).then_some(code)
+}
+
+/// A `GET /convert` request that passed validation.
+#[derive(Debug)]
+pub struct Conversion {
+    pub from: String,
+    pub to: String,
+    pub amount: Decimal,
+}
+
+/// Parses and checks the query of `GET /convert`, reporting every problem
+/// in a single `ValidationError`.
+pub fn conversion(query: &ConvertQuery) -> Result<Conversion, AppError> {
+    let mut errors = HashMap::new();
+    let mut code = |key: &str, value: &Option<String>| match value.as_deref() {
+        None => {
+            errors.insert(key.to_string(), "is required".to_string());
+            None
+        }
+        Some(value) => {
+            let code = currency_code(value);
+            if code.is_none() {
+                errors.insert(
+                    key.to_string(),
+                    format!("must be a three-letter ISO 4217 code (got '{}')", value),
+                );
+            }
+            code
+        }
+    };
+    let from = code("from", &query.from);
+    let to = code("to", &query.to);
+
+    let amount = match &query.amount {
+        None => {
+            errors.insert("amount".to_string(), "is required".to_string());
+            None
+        }
+        amount => parse::<Decimal>(&mut errors, "amount", amount, "a number"),
+    };
+    if amount.is_some_and(|amount| amount.is_sign_negative()) {
+        errors.insert("amount".to_string(), "must not be negative".to_string());
+    }
+
+    match (from, to, amount) {
+        (Some(from), Some(to), Some(amount)) if errors.is_empty() => Ok(Conversion { from, to, amount }),
+        _ => Err(AppError::ValidationError(errors)),
+    }
+}
+
+/// Trims an optional text field; blank becomes `None`. Records an error if it
+/// is longer than `max_length` characters.
+fn text(
+    errors: &mut HashMap<String, String>,
+    key: &str,
+    value: Option<String>,
+    max_length: usize,
+) -> Option<String> {
+    let value = value?.trim().to_string();
+    if value.is_empty() {
+        return None;
+    }
+    if value.chars().count() > max_length {
+        errors.insert(key.to_string(), format!("must be at most {} characters", max_length));
+        return None;
+    }
+    Some(value)
+}
+
+/// Checks an optional ISO 3166-1 code of `length` characters matching `valid`;
+/// blank becomes `None`. Codes are stored uppercase.
+fn iso_code(
+    errors: &mut HashMap<String, String>,
+    key: &str,
+    value: Option<String>,
+    length: usize,
+    valid: fn(char) -> bool,
+) -> Option<String> {
+    let value = value?.trim().to_uppercase();
+    if value.is_empty() {
+        return None;
+    }
+    if value.len() != length || !value.chars().all(valid) {
+        errors.insert(
+            key.to_string(),
+            format!(
+                "must be an ISO 3166-1 {} code (got '{}')",
+                key.trim_end_matches("_code").replace("alpha", "alpha-"),
+                value
+            ),
+        );
+        return None;
+    }
+    Some(value)
+}
+
+/// A `ValidationError` with a single entry.
+pub fn invalid(field: &str, message: &str) -> AppError {
+    let mut details = HashMap::new();
+    details.insert(field.to_string(), message.to_string());
+    AppError::ValidationError(details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(column: &'static str, descending: bool) -> SortKey {
+        SortKey { column, descending }
+    }
+
+    #[test]
+    fn sort_keys_in_order() {
+        assert_eq!(
+            sort_keys("region,-estimated_gdp, +name").unwrap(),
+            [
+                key("region", false),
+                key("estimated_gdp", true),
+                key("name", false)
+            ]
+        );
+        assert_eq!(
+            sort_keys("primary_currency").unwrap(),
+            [key("currency_code", false)]
+        );
+    }
+
+    #[test]
+    fn legacy_sorts_are_aliases() {
+        assert_eq!(sort_keys("gdp_desc").unwrap(), [key("estimated_gdp", true)]);
+        assert_eq!(sort_keys("name_asc").unwrap(), [key("name", false)]);
+    }
+
+    #[test]
+    fn rejects_bad_sort_keys() {
+        let unknown = sort_keys("name,-gdp").unwrap_err();
+        assert!(unknown.starts_with("unknown sort field '-gdp'"), "{}", unknown);
+        assert!(unknown.contains("gdp_desc"), "{}", unknown);
+        assert_eq!(
+            sort_keys("name,-name").unwrap_err(),
+            "sort field 'name' is listed more than once"
+        );
+        assert!(sort_keys("").is_err());
+        assert!(sort_keys("name,").is_err());
+    }
+
+    fn payload(json: serde_json::Value) -> CountryPayload {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn errors(result: Result<CountryRecord, AppError>) -> HashMap<String, String> {
+        match result {
+            Err(AppError::ValidationError(details)) => details,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn creates_a_record() {
+        let record = country_payload(
+            payload(serde_json::json!({
+                "name": "  Wakanda ",
+                "population": 6000000,
+                "capital": "Birnin Zana",
+                "region": "",
+                "currencies": [{ "code": "wkd", "name": "Wakandan dollar" }, { "code": "USD" }],
+                "alt_spellings": ["WK", " "],
+                "alpha2_code": "wk",
+                "numeric_code": null
+            })),
+            None,
+        )
+        .unwrap();
+        assert_eq!(record.name, "Wakanda");
+        assert_eq!(record.population, 6_000_000);
+        assert_eq!(record.capital.as_deref(), Some("Birnin Zana"));
+        assert_eq!(record.region, None);
+        let codes: Vec<&str> = record.currencies.iter().map(|c| c.code.as_str()).collect();
+        assert_eq!(codes, ["WKD", "USD"]);
+        assert_eq!(record.currencies[0].name.as_deref(), Some("Wakandan dollar"));
+        assert_eq!(record.alt_spellings, ["WK"]);
+        assert_eq!(record.alpha2_code.as_deref(), Some("WK"));
+        assert_eq!(record.numeric_code, None);
+    }
+
+    #[test]
+    fn create_requires_name_and_population() {
+        let details = errors(country_payload(payload(serde_json::json!({})), None));
+        assert_eq!(details["name"], "is required");
+        assert_eq!(details["population"], "is required");
+
+        let details = errors(country_payload(
+            payload(serde_json::json!({ "name": " ", "population": -1 })),
+            None,
+        ));
+        assert_eq!(details["name"], "must not be blank");
+        assert_eq!(details["population"], "must not be negative");
+    }
+
+    #[test]
+    fn reports_every_bad_field() {
+        let details = errors(country_payload(
+            payload(serde_json::json!({
+                "name": "x".repeat(MAX_TEXT_LENGTH + 1),
+                "population": 1,
+                "currencies": [{ "code": "USD" }, { "code": "US" }, { "code": "usd" }],
+                "alpha2_code": "WKD",
+                "alpha3_code": "W1D",
+                "numeric_code": "12a"
+            })),
+            None,
+        ));
+        assert_eq!(details["name"], "must be at most 255 characters");
+        assert_eq!(
+            details["currencies[1].code"],
+            "must be a three-letter ISO 4217 code (got 'US')"
+        );
+        assert_eq!(
+            details["currencies[2].code"],
+            "currency 'USD' is listed more than once"
+        );
+        assert_eq!(
+            details["alpha2_code"],
+            "must be an ISO 3166-1 alpha-2 code (got 'WKD')"
+        );
+        assert_eq!(
+            details["alpha3_code"],
+            "must be an ISO 3166-1 alpha-3 code (got 'W1D')"
+        );
+        assert_eq!(
+            details["numeric_code"],
+            "must be an ISO 3166-1 numeric code (got '12A')"
+        );
+        assert_eq!(details.len(), 6);
+    }
+
+    #[test]
+    fn patch_keeps_absent_fields_and_clears_null_ones() {
+        let base = country_payload(
+            payload(serde_json::json!({
+                "name": "Wakanda",
+                "population": 6000000,
+                "capital": "Birnin Zana",
+                "region": "Africa",
+                "currencies": [{ "code": "WKD" }]
+            })),
+            None,
+        )
+        .unwrap();
+        let record = country_payload(
+            payload(serde_json::json!({ "population": 7000000, "capital": null })),
+            Some(base),
+        )
+        .unwrap();
+        assert_eq!(record.name, "Wakanda");
+        assert_eq!(record.population, 7_000_000);
+        assert_eq!(record.capital, None);
+        assert_eq!(record.region.as_deref(), Some("Africa"));
+        assert_eq!(record.currencies.len(), 1);
+    }
+
+    #[test]
+    fn unknown_payload_fields_are_rejected() {
+        let err = serde_json::from_value::<CountryPayload>(serde_json::json!({ "gdp": 1 }))
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown field `gdp`"), "{}", err);
+    }
+}